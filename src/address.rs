@@ -0,0 +1,225 @@
+use std::fmt;
+use std::result;
+use std::str::FromStr;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use rustc_serialize::hex::{FromHex, ToHex};
+use secp256k1::key::PublicKey;
+use base58;
+use constants::{
+    SHA256_ADDRESS_VERSION, HASH160_ADDRESS_VERSION, SCRIPT_ADDRESS_VERSION,
+    SCHNORR_SHA256_ADDRESS_VERSION, SCHNORR_HASH160_ADDRESS_VERSION,
+};
+use error::{Error, Result};
+use script::Script;
+use wallet::{hash_public_key, HashType};
+
+/// Where coins can be sent: the hash of a public key, written in Base58
+/// after a version byte identifying the network and how the key was
+/// hashed, and followed by a checksum so mistyped addresses are rejected.
+/// Script addresses hold the hash of a script instead, such as a multisig
+/// script. Coins paid to Schnorr addresses are spent with Schnorr rather
+/// than ECDSA signatures.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address {
+    hash_type: HashType,
+    hash: Vec<u8>,
+    script: bool,
+    schnorr: bool,
+}
+
+impl Address {
+    pub fn from_public_key(public_key: &PublicKey, hash_type: HashType) -> Address {
+        Address {
+            hash_type,
+            hash: hash_public_key(public_key, hash_type).from_hex().unwrap(),
+            script: false,
+            schnorr: false,
+        }
+    }
+
+    pub fn from_script(script: &Script) -> Address {
+        Address {
+            hash_type: HashType::Hash160,
+            hash: script.hash().to_vec(),
+            script: true,
+            schnorr: false,
+        }
+    }
+
+    /// The address of an output locked to the hex `pubkey_hash`.
+    pub fn from_pubkey_hash(pubkey_hash: &str, hash_type: HashType) -> Result<Address> {
+        let hash = pubkey_hash.from_hex().map_err(|_| Error::InvalidAddress)?;
+        if hash.len() != hash_type.size() {
+            return Err(Error::InvalidAddress);
+        }
+
+        Ok(Address {
+            hash_type,
+            hash,
+            script: false,
+            schnorr: false,
+        })
+    }
+
+    /// The address of an output locked to the hex `script_hash`.
+    pub fn from_script_hash(script_hash: &str) -> Result<Address> {
+        let mut address = Address::from_pubkey_hash(script_hash, HashType::Hash160)?;
+        address.script = true;
+        Ok(address)
+    }
+
+    pub fn hash_type(&self) -> HashType {
+        self.hash_type
+    }
+
+    /// The hex public key hash that outputs paying this address are locked
+    /// to.
+    pub fn pubkey_hash(&self) -> String {
+        self.hash.to_hex()
+    }
+
+    /// The hex hash of the script of a script address.
+    pub fn script_hash(&self) -> Option<String> {
+        if self.script {
+            Some(self.hash.to_hex())
+        } else {
+            None
+        }
+    }
+
+    pub fn is_script(&self) -> bool {
+        self.script
+    }
+
+    /// The address of the same public key hash whose coins are spent with
+    /// Schnorr signatures.
+    pub fn to_schnorr(&self) -> Result<Address> {
+        if self.script {
+            return Err(Error::InvalidAddress);
+        }
+        let mut address = self.clone();
+        address.schnorr = true;
+        Ok(address)
+    }
+
+    pub fn is_schnorr(&self) -> bool {
+        self.schnorr
+    }
+
+    fn version(&self) -> u8 {
+        match self.hash_type {
+            _ if self.script => SCRIPT_ADDRESS_VERSION,
+            HashType::Sha256 if self.schnorr => SCHNORR_SHA256_ADDRESS_VERSION,
+            HashType::Hash160 if self.schnorr => SCHNORR_HASH160_ADDRESS_VERSION,
+            HashType::Sha256 => SHA256_ADDRESS_VERSION,
+            HashType::Hash160 => HASH160_ADDRESS_VERSION,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut data = vec![self.version()];
+        data.extend_from_slice(&self.hash);
+        write!(f, "{}", base58::encode_check(&data))
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Address> {
+        let data = base58::decode_check(s).ok_or(Error::InvalidAddress)?;
+        let (hash_type, script, schnorr) = match data.first() {
+            Some(&SHA256_ADDRESS_VERSION) => (HashType::Sha256, false, false),
+            Some(&HASH160_ADDRESS_VERSION) => (HashType::Hash160, false, false),
+            Some(&SCRIPT_ADDRESS_VERSION) => (HashType::Hash160, true, false),
+            Some(&SCHNORR_SHA256_ADDRESS_VERSION) => (HashType::Sha256, false, true),
+            Some(&SCHNORR_HASH160_ADDRESS_VERSION) => (HashType::Hash160, false, true),
+            _ => return Err(Error::InvalidAddress),
+        };
+        if data.len() != hash_type.size() + 1 {
+            return Err(Error::InvalidAddress);
+        }
+
+        Ok(Address {
+            hash_type,
+            hash: data[1..].to_vec(),
+            script,
+            schnorr,
+        })
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Address, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|err: Error| de::Error::custom(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH160: &str = "eb15231dfceb60925886b67d065299925915aeb1";
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn assert_invalid(s: &str) {
+        match s.parse::<Address>() {
+            Err(Error::InvalidAddress) => (),
+            other => panic!("expected an invalid address, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_every_kind() {
+        let hash160 = Address::from_pubkey_hash(HASH160, HashType::Hash160).unwrap();
+        let sha256 = Address::from_pubkey_hash(SHA256, HashType::Sha256).unwrap();
+        let addresses = vec![
+            hash160.to_schnorr().unwrap(),
+            sha256.to_schnorr().unwrap(),
+            Address::from_script_hash(HASH160).unwrap(),
+            hash160,
+            sha256,
+        ];
+
+        for address in addresses {
+            let parsed: Address = address.to_string().parse().unwrap();
+            assert_eq!(parsed, address);
+            assert_eq!(parsed.pubkey_hash(), address.pubkey_hash());
+        }
+    }
+
+    #[test]
+    fn rejects_an_unknown_version() {
+        // A Bitcoin address has a valid checksum but not one of our versions
+        let mut data = vec![0x00];
+        data.extend(HASH160.from_hex().unwrap());
+        assert_invalid(&base58::encode_check(&data));
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let address = Address::from_pubkey_hash(HASH160, HashType::Hash160).unwrap();
+        let mut s = address.to_string();
+        let last = if s.ends_with('2') { '3' } else { '2' };
+        s.pop();
+        s.push(last);
+        assert_invalid(&s);
+    }
+
+    #[test]
+    fn rejects_a_hash_of_the_wrong_size() {
+        let mut data = vec![SHA256_ADDRESS_VERSION];
+        data.extend(HASH160.from_hex().unwrap());
+        assert_invalid(&base58::encode_check(&data));
+        assert!(Address::from_pubkey_hash(HASH160, HashType::Sha256).is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::{HashMap, HashSet};
+use address::Address;
+use blockchain::Blockchain;
+use constants::COINBASE_MATURITY;
+use mempool::MemPool;
+use transaction::{TXOutput, TX};
+use utxo;
+
+/// The coins held by a set of addresses.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Balance {
+    /// Spendable coins with at least the minimum number of confirmations.
+    pub confirmed: u64,
+    /// Coins with fewer confirmations, including those still in the mempool.
+    pub unconfirmed: u64,
+    /// Block rewards that cannot be spent until they mature.
+    pub immature: u64,
+    /// Coins in time-locked outputs, such as vesting tranches, and HTLCs
+    /// paid to the addresses, which are spent with their own commands rather
+    /// than `send`, whether confirmed or not.
+    pub locked: u64,
+}
+
+impl Balance {
+    pub fn total(&self) -> u64 {
+        self.confirmed + self.unconfirmed + self.immature + self.locked
+    }
+}
+
+/// The balance of `addresses`, counting coins with fewer than
+/// `min_confirmations` as unconfirmed. Coins already spent by a transaction
+/// in the mempool are left out.
+pub fn balance(
+    bc: &Blockchain,
+    mp: &MemPool,
+    addresses: &[Address],
+    min_confirmations: usize,
+) -> Balance {
+    let own: HashSet<&Address> = addresses.iter().collect();
+    let is_own = |output: &TXOutput| output.payee().is_some_and(|address| own.contains(&address));
+
+    // The confirmations of each transaction in the blockchain, and whether
+    // it is a coinbase
+    let mut confirmations = HashMap::new();
+    for (depth, (_, block)) in bc.iter().enumerate() {
+        for tx in block.transactions.iter() {
+            let coinbase = matches!(*tx, TX::Coinbase(_));
+            confirmations.insert(tx.id(), (depth + 1, coinbase));
+        }
+    }
+
+    let mut balance = Balance::default();
+    for (txid, entries) in mp.utxo(bc) {
+        let (depth, coinbase) = confirmations[&txid];
+        for entry in entries.iter().filter(|entry| is_own(&entry.output)) {
+            if entry.output.script.is_some() {
+                balance.locked += entry.output.value;
+            } else if coinbase && depth < COINBASE_MATURITY {
+                balance.immature += entry.output.value;
+            } else if depth < min_confirmations {
+                balance.unconfirmed += entry.output.value;
+            } else {
+                balance.confirmed += entry.output.value;
+            }
+        }
+    }
+
+    for tx in mp.transactions() {
+        let txid = tx.id();
+        for entry in utxo::new_entries(tx) {
+            if !is_own(&entry.output) || mp.spender(&txid, entry.vout).is_some() {
+                continue;
+            }
+            if entry.output.script.is_some() {
+                balance.locked += entry.output.value;
+            } else {
+                balance.unconfirmed += entry.output.value;
+            }
+        }
+    }
+
+    balance
+}
@@ -0,0 +1,145 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+pub fn encode(data: &[u8]) -> String {
+    // Repeatedly divide the big-endian number by 58
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    // Leading zero bytes are written as leading '1's
+    let zeros = data.iter().take_while(|&&byte| byte == 0).count();
+    let mut encoded = vec![ALPHABET[0]; zeros];
+    encoded.extend(digits.iter().rev().map(|&digit| ALPHABET[digit as usize]));
+
+    String::from_utf8(encoded).unwrap()
+}
+
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.bytes() {
+        let mut carry = ALPHABET.iter().position(|&a| a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+
+    let zeros = s.bytes().take_while(|&c| c == ALPHABET[0]).count();
+    let mut decoded = vec![0; zeros];
+    decoded.extend(bytes.iter().rev());
+
+    Some(decoded)
+}
+
+fn checksum(data: &[u8]) -> Vec<u8> {
+    let mut first = [0; 32];
+    let mut hash = Sha256::new();
+    hash.input(data);
+    hash.result(&mut first);
+
+    let mut second = [0; 32];
+    let mut hash = Sha256::new();
+    hash.input(&first);
+    hash.result(&mut second);
+
+    second[..4].to_vec()
+}
+
+/// Encodes `data` followed by the first four bytes of its double SHA-256.
+pub fn encode_check(data: &[u8]) -> String {
+    let mut payload = data.to_vec();
+    payload.extend(checksum(data));
+    encode(&payload)
+}
+
+/// Decodes a string made by `encode_check`, returning `None` if it is not
+/// valid Base58 or the checksum does not match.
+pub fn decode_check(s: &str) -> Option<Vec<u8>> {
+    let payload = decode(s)?;
+    if payload.len() < 4 {
+        return None;
+    }
+    let (data, check) = payload.split_at(payload.len() - 4);
+    if checksum(data) != check {
+        return None;
+    }
+
+    Some(data.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::FromHex;
+
+    // Known answers shared with other Base58 implementations
+    const VECTORS: &[(&str, &str)] = &[
+        ("", ""),
+        ("61", "2g"),
+        ("626262", "a3gV"),
+        ("636363", "aPEr"),
+        ("73696d706c792061206c6f6e6720737472696e67", "2cFupjhnEsSn59qHXstmK2ffpLv2"),
+        ("00eb15231dfceb60925886b67d065299925915aeb172c06647", "1NS17iag9jJgTHD1VXjvLCEnZuQ3rJDE9L"),
+        ("516b6fcd0f", "ABnLTmg"),
+        ("bf4f89001e670274dd", "3SEo3LWLoPntC"),
+        ("572e4794", "3EFU7m"),
+        ("ecac89cad93923c02321", "EJDM8drfXA6uyA"),
+        ("10c8511e", "Rt5zm"),
+        ("00000000000000000000", "1111111111"),
+    ];
+
+    #[test]
+    fn encodes_known_vectors() {
+        for &(hex, encoded) in VECTORS {
+            assert_eq!(encode(&hex.from_hex().unwrap()), encoded);
+        }
+    }
+
+    #[test]
+    fn decodes_known_vectors() {
+        for &(hex, encoded) in VECTORS {
+            assert_eq!(decode(encoded), Some(hex.from_hex().unwrap()));
+        }
+    }
+
+    #[test]
+    fn rejects_characters_outside_the_alphabet() {
+        for s in &["0", "O", "I", "l", "3SEo3LWL0PntC", "a3g V"] {
+            assert_eq!(decode(s), None);
+        }
+    }
+
+    #[test]
+    fn checks_known_vectors() {
+        // The version byte and public key hash of a Bitcoin address
+        let data = "00010966776006953d5567439e5e39f86a0d273bee".from_hex().unwrap();
+        assert_eq!(encode_check(&data), "16UwLL9Risc3QfPqBUvKofHmBQ7wMtjvM");
+        assert_eq!(decode_check("16UwLL9Risc3QfPqBUvKofHmBQ7wMtjvM"), Some(data));
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        // The last digit changed, the string truncated, and too short for a checksum
+        assert_eq!(decode_check("16UwLL9Risc3QfPqBUvKofHmBQ7wMtjvN"), None);
+        assert_eq!(decode_check("16UwLL9Risc3QfPqBUvKofHmBQ7wM"), None);
+        assert_eq!(decode_check(""), None);
+    }
+}
@@ -1,19 +1,45 @@
 use block::Block;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use num::bigint::BigInt;
 use num::traits::One;
 use num::Num;
-use constants::{DIFFICULTY, BLOCKCHAIN};
+use constants::{DIFFICULTY, BLOCKCHAIN, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN};
 use std::fs::File;
 use std::io::prelude::*;
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
 use serialize::{deserialize, serialize};
-use transaction::{TX, CoinbaseTX};
+use transaction::{TX, CoinbaseTX, StandardTX, TXSource, REWARD};
 use error::{Result, Error};
+use address::Address;
+use verify;
 
 #[derive(Serialize, Deserialize)]
 pub struct Blockchain {
     blocks: HashMap<String, Block>,
     pub last_block_hash: String,
+    /// The height from which block rewards must mature before they are
+    /// spent, and block timestamps and lock times follow the median time
+    /// past. Chains written before these rules may break them, so for those
+    /// they apply after the height they had when first opened since.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upgrade_height: Option<usize>,
+}
+
+// The blockchain with the transactions of a block being validated on top,
+// whose outputs later transactions in the block may spend
+struct BlockView<'a> {
+    blockchain: &'a Blockchain,
+    transactions: HashMap<String, &'a TX>,
+}
+
+impl<'a> TXSource for BlockView<'a> {
+    fn find_transaction(&self, txid: &str) -> Option<&TX> {
+        match self.transactions.get(txid) {
+            Some(&tx) => Some(tx),
+            None => self.blockchain.find_transaction(txid),
+        }
+    }
 }
 
 
@@ -22,7 +48,8 @@ impl Blockchain {
         self.blocks.get(hash)
     }
 
-    fn validate_block(&self, prev_block_hash: &str, block: &Block) -> Result<()> {
+    // Validates `block` as the block at `height`, following `prev_block_hash`
+    fn validate_block(&self, prev_block_hash: &str, block: &Block, height: usize) -> Result<()> {
         use self::Error::*;
         let target = BigInt::one() << (256 - DIFFICULTY);
         let hash_int = BigInt::from_str_radix(&block.hash(), 16).unwrap();
@@ -33,20 +60,77 @@ impl Blockchain {
             return Err(InvalidNonce)
         }
 
-        // Verify each transaction
+        // A block may not be timestamped before the median time past, nor
+        // too far ahead of the clock, and its lock times are compared with
+        // the median time past, which its miner cannot move forward
+        let upgraded = self.upgrade_height.is_some_and(|from| height >= from);
+        let median_time_past = self.median_time_past_at(prev_block_hash);
+        let lock_time = if upgraded {
+            let latest = now().saturating_add(MAX_FUTURE_BLOCK_TIME);
+            let timestamp = u64::try_from(block.timestamp).map_err(|_| InvalidTimestamp)?;
+            if timestamp < median_time_past || timestamp > latest {
+                return Err(InvalidTimestamp);
+            }
+            median_time_past
+        } else {
+            block.timestamp as u64
+        };
+
+        let view = BlockView {
+            blockchain: self,
+            transactions: block.transactions.iter().map(|tx| (tx.id(), tx)).collect(),
+        };
+
+        // A transaction may only spend outputs of the transactions before it
+        // in the block, other than the block reward, and each output once
+        let mut earlier = HashSet::new();
+        let mut spent = HashSet::new();
         for tx in block.transactions.iter() {
-            match tx {
-                &TX::Coinbase(_) => {},
-                &TX::Standard(ref tx) => tx.verify(&self)?
+            if let TX::Standard(ref stx) = *tx {
+                for input in stx.inputs.iter() {
+                    match view.transactions.get(&input.txid) {
+                        Some(&&TX::Coinbase(_)) if upgraded => return Err(ImmatureCoinbase),
+                        Some(_) if !earlier.contains(&input.txid) => return Err(TransactionMissing),
+                        _ => {}
+                    }
+                    if !spent.insert((&input.txid, input.vout)) {
+                        return Err(OutputAlreadySpent);
+                    }
+                }
+            }
+            earlier.insert(tx.id());
+        }
+
+        // Verify the inputs of every transaction across threads, then the
+        // rest of each transaction in turn, failing on the first error in
+        // block order
+        let txs: Vec<&StandardTX> = block.transactions
+            .iter()
+            .filter_map(|tx| match *tx {
+                TX::Coinbase(_) => None,
+                TX::Standard(ref tx) => Some(tx),
+            })
+            .collect();
+        let mut input_results = verify::verify_inputs(&view, &txs).into_iter();
+        let mut fees: u64 = 0;
+        for tx in txs {
+            for result in input_results.by_ref().take(tx.inputs.len()) {
+                result?;
+            }
+            tx.verify_outputs(&view)?;
+            fees = fees.checked_add(tx.fee(&view)?).ok_or(InvalidTransaction)?;
+            if upgraded {
+                tx.check_maturity(self, height)?;
             }
+            tx.check_lock_times(self, height, lock_time)?;
         }
 
         // Check that there is at most 1 Coinbase transaction
         let num_coinbase: u64 = block.transactions.iter()
             .map(|tx| {
-                match tx {
-                    &TX::Coinbase(_) => 1,
-                    &TX::Standard(_) => 0
+                match *tx {
+                    TX::Coinbase(_) => 1,
+                    TX::Standard(_) => 0
                 }
             })
             .sum();
@@ -54,22 +138,45 @@ impl Blockchain {
             return Err(TooManyCoinbase)
         }
 
+        // The miner may claim no more than the block reward and the fees
+        let claimed = block.transactions
+            .iter()
+            .filter(|tx| matches!(**tx, TX::Coinbase(_)))
+            .flat_map(|tx| tx.outputs().iter())
+            .try_fold(0u64, |acc, output| acc.checked_add(output.value))
+            .ok_or(InvalidTransaction)?;
+        if claimed > REWARD.saturating_add(fees) {
+            return Err(ExcessiveReward)
+        }
+
         Ok(())
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        self.validate_block(&self.last_block_hash, &block)?;
+        self.validate_block(&self.last_block_hash, &block, self.height() + 1)?;
         self.last_block_hash = block.hash();
         self.blocks.insert(block.hash(), block);
         Ok(())
     }
 
+    /// Removes the last block, returning it so that its transactions can go
+    /// back to the mempool. The genesis block cannot be removed.
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        if self.height() == 0 {
+            return Err(Error::GenesisDisconnect);
+        }
+        let block = self.blocks.remove(&self.last_block_hash).unwrap();
+        self.last_block_hash = block.prev_block_hash.clone();
+
+        Ok(block)
+    }
+
     fn validate_chain(&self) -> Result<()> {
         let chain: Vec<(&str, &Block)> = self.iter().collect();
         let mut prev_hash = "".to_owned();
 
-        for &(hash, block) in chain.iter().rev() {
-            self.validate_block(&prev_hash, block)?;
+        for (height, &(hash, block)) in chain.iter().rev().enumerate() {
+            self.validate_block(&prev_hash, block, height)?;
             prev_hash = hash.to_owned();
         }
         Ok(())
@@ -82,8 +189,17 @@ impl Blockchain {
         let mut contents = String::new();
         file.read_to_string(&mut contents).unwrap();
 
-        let blockchain: Blockchain = deserialize(&contents);
+        Blockchain::load(&contents)
+    }
+
+    // Reads and validates a saved blockchain, enforcing coinbase maturity
+    // on the blocks after its tip if it was saved before the rule existed
+    fn load(contents: &str) -> Result<Blockchain> {
+        let mut blockchain: Blockchain = deserialize(contents);
         blockchain.validate_chain()?;
+        if blockchain.upgrade_height.is_none() {
+            blockchain.upgrade_height = Some(blockchain.height() + 1);
+        }
 
         Ok(blockchain)
     }
@@ -93,14 +209,15 @@ impl Blockchain {
         file.write_all(serialize(self).as_bytes()).unwrap()
     }
 
-    pub fn new(genesis_address: &str) -> Result<Blockchain> {
+    pub fn new(genesis_address: &Address) -> Result<Blockchain> {
         let mut blockchain = Blockchain {
             blocks: HashMap::new(),
             last_block_hash: String::new(),
+            upgrade_height: Some(0),
         };
 
         let prev_block_hash = "".to_owned();
-        let tx = TX::Coinbase(CoinbaseTX::new(genesis_address.to_owned()));
+        let tx = TX::Coinbase(CoinbaseTX::new(genesis_address, 0));
         let genesis = Block::mine(vec![tx], prev_block_hash);
         blockchain.add_block(genesis)?;
 
@@ -113,14 +230,85 @@ impl Blockchain {
             .find(|tx| tx.id() == txid)
     }
 
-    pub fn iter(&self) -> IterBlockchain {
+    /// The height of the block holding the transaction `txid`.
+    pub fn transaction_height(&self, txid: &str) -> Option<usize> {
+        self.transaction_block(txid).map(|(height, _)| height)
+    }
+
+    /// The block holding a transaction, with its height.
+    pub fn transaction_block(&self, txid: &str) -> Option<(usize, &Block)> {
+        let (depth, (_, block)) = self.iter().enumerate().find(|&(_, (_, block))| {
+            block.transactions.iter().any(|tx| tx.id() == txid)
+        })?;
+        Some((self.height() - depth, block))
+    }
+
+    /// The median timestamp of the last blocks, which the next block may
+    /// not precede and its lock times are compared with.
+    pub fn median_time_past(&self) -> u64 {
+        self.median_time_past_at(&self.last_block_hash)
+    }
+
+    // The median time past of the blocks up to the block `hash`, or 0 if
+    // there are none
+    fn median_time_past_at(&self, hash: &str) -> u64 {
+        let blocks = IterBlockchain {
+            blockchain: self,
+            current_hash: hash,
+        };
+        let mut timestamps: Vec<u64> = blocks
+            .take(MEDIAN_TIME_SPAN)
+            .map(|(_, block)| block.timestamp.max(0) as u64)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).cloned().unwrap_or(0)
+    }
+
+    /// The number of blocks after the genesis block.
+    pub fn height(&self) -> usize {
+        self.iter().count().saturating_sub(1)
+    }
+
+    pub fn iter(&self) -> IterBlockchain<'_> {
         IterBlockchain {
-            blockchain: &self,
+            blockchain: self,
             current_hash: &self.last_block_hash,
         }
     }
 }
 
+#[cfg(test)]
+impl Blockchain {
+    /// A chain whose genesis block holds `transactions`, unchecked, so that
+    /// tests can fund outputs without mining. It is not saved when dropped.
+    pub fn unchecked(transactions: Vec<TX>) -> ::std::mem::ManuallyDrop<Blockchain> {
+        let genesis = Block {
+            timestamp: now() as i64,
+            transactions,
+            prev_block_hash: String::new(),
+            nonce: 0,
+        };
+        ::std::mem::ManuallyDrop::new(Blockchain {
+            last_block_hash: genesis.hash(),
+            blocks: vec![(genesis.hash(), genesis)].into_iter().collect(),
+            upgrade_height: Some(0),
+        })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl TXSource for Blockchain {
+    fn find_transaction(&self, txid: &str) -> Option<&TX> {
+        Blockchain::find_transaction(self, txid)
+    }
+}
+
 impl Drop for Blockchain {
     fn drop(&mut self) {
         self.save();
@@ -136,8 +324,8 @@ impl<'a> Iterator for IterBlockchain<'a> {
     type Item = (&'a str, &'a Block);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let hash = &self.current_hash.clone();
-        let block = self.blockchain.get_block(&hash);
+        let hash = self.current_hash;
+        let block = self.blockchain.get_block(hash);
         match block {
             Some(v) => {
                 self.current_hash = &v.prev_block_hash;
@@ -147,3 +335,131 @@ impl<'a> Iterator for IterBlockchain<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coinselect::Coin;
+    use constants::LOCKTIME_THRESHOLD;
+    use script::{Op, Script};
+    use std::mem::ManuallyDrop;
+    use transaction::{TXInput, TXOutput};
+
+    // Outputs locked to a script anyone can satisfy, so that the tests need
+    // no keys
+    fn redeem_script() -> Script {
+        Script::new(vec![Op::Push(vec![1])])
+    }
+
+    fn coinbase() -> TX {
+        TX::Coinbase(CoinbaseTX::new(&Address::from_script(&redeem_script()), 0))
+    }
+
+    // Spends the first output of `tx` in full
+    fn spend(tx: &TX) -> TX {
+        let output = tx.outputs()[0].clone();
+        let value = output.value;
+        let mut input = TXInput::unsigned(&Coin { txid: tx.id(), vout: 0, output });
+        input.unlock = Some(Script::new(vec![]));
+        input.redeem_script = Some(redeem_script());
+        TX::Standard(StandardTX {
+            inputs: vec![input],
+            outputs: vec![TXOutput::new(value, &Address::from_script(&redeem_script()))],
+            replaceable: false,
+            lock_time: 0,
+            memo: String::new(),
+        })
+    }
+
+    // Mines a block with the given timestamp
+    fn mine_at(transactions: Vec<TX>, prev_block_hash: String, timestamp: i64) -> Block {
+        let target = BigInt::one() << (256 - DIFFICULTY);
+        let mut block = Block {
+            timestamp,
+            transactions,
+            prev_block_hash,
+            nonce: 0,
+        };
+        while BigInt::from_str_radix(&block.hash(), 16).unwrap() > target {
+            block.nonce += 1;
+        }
+        block
+    }
+
+    fn assert_rejected(result: Result<()>, expected: Error) {
+        match result {
+            Err(ref err) if err.to_string() == expected.to_string() => {}
+            other => panic!("expected {:?}, got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn block_timestamps_are_bounded() {
+        let address = Address::from_script(&redeem_script());
+        let mut blockchain = ManuallyDrop::new(Blockchain::new(&address).unwrap());
+        let median_time_past = blockchain.median_time_past() as i64;
+        let prev_block_hash = blockchain.last_block_hash.clone();
+
+        let early = mine_at(vec![coinbase()], prev_block_hash.clone(), median_time_past - 1);
+        assert_rejected(blockchain.add_block(early), Error::InvalidTimestamp);
+        let late = now() + MAX_FUTURE_BLOCK_TIME + 60;
+        let late = mine_at(vec![coinbase()], prev_block_hash.clone(), late as i64);
+        assert_rejected(blockchain.add_block(late), Error::InvalidTimestamp);
+
+        let block = mine_at(vec![coinbase()], prev_block_hash, median_time_past);
+        assert!(blockchain.add_block(block).is_ok());
+    }
+
+    #[test]
+    fn lock_times_follow_the_median_time_past() {
+        let address = Address::from_script(&redeem_script());
+        let mut blockchain = ManuallyDrop::new(Blockchain::new(&address).unwrap());
+        let median_time_past = blockchain.median_time_past();
+        assert!(median_time_past >= LOCKTIME_THRESHOLD);
+
+        // The block's own timestamp cannot unlock the transaction
+        let locked = TX::Standard(StandardTX {
+            inputs: vec![],
+            outputs: vec![],
+            replaceable: false,
+            lock_time: median_time_past + 60,
+            memo: String::new(),
+        });
+        let prev_block_hash = blockchain.last_block_hash.clone();
+        let block = mine_at(vec![locked, coinbase()], prev_block_hash, (median_time_past + 60) as i64);
+        assert_rejected(blockchain.add_block(block), Error::TransactionLocked);
+    }
+
+    #[test]
+    fn opens_a_chain_that_spent_an_immature_coinbase_before_the_rule() {
+        let genesis = Block::mine(vec![coinbase()], String::new());
+        let block = Block::mine(vec![spend(&genesis.transactions[0]), coinbase()], genesis.hash());
+        let last_block_hash = block.hash();
+        let old = ManuallyDrop::new(Blockchain {
+            blocks: vec![(genesis.hash(), genesis), (block.hash(), block)].into_iter().collect(),
+            last_block_hash,
+            upgrade_height: None,
+        });
+        let contents = serialize(&*old);
+        assert!(!contents.contains("upgrade_height"));
+
+        let mut blockchain = ManuallyDrop::new(Blockchain::load(&contents).unwrap());
+        assert_eq!(blockchain.upgrade_height, Some(2));
+
+        // Blocks after the tip follow the rule
+        let coinbase_tx = blockchain.iter().next().unwrap().1.transactions[1].clone();
+        let prev_block_hash = blockchain.last_block_hash.clone();
+        let block = Block::mine(vec![spend(&coinbase_tx), coinbase()], prev_block_hash);
+        assert_rejected(blockchain.add_block(block), Error::ImmatureCoinbase);
+    }
+
+    #[test]
+    fn new_chains_reject_an_immature_coinbase_spend() {
+        let address = Address::from_script(&redeem_script());
+        let mut blockchain = ManuallyDrop::new(Blockchain::new(&address).unwrap());
+        let genesis_tx = blockchain.iter().next().unwrap().1.transactions[0].clone();
+        let prev_block_hash = blockchain.last_block_hash.clone();
+        let block = Block::mine(vec![spend(&genesis_tx), coinbase()], prev_block_hash);
+        assert_rejected(blockchain.add_block(block), Error::ImmatureCoinbase);
+    }
+}
@@ -1,52 +1,501 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use rustc_serialize::hex::{FromHex, ToHex};
+use secp256k1::Secp256k1;
+use secp256k1::key::PublicKey;
 use blockchain::Blockchain;
 use block::Block;
-use utxo;
+use utxo::{self, UTXO};
 use transaction::*;
-use wallet::{Wallet, Wallets, hash_public_key};
+use wallet::{serialize_public_key, Export, HashType, Wallet, Wallets};
+use address::Address;
+use hd::{self, ExtendedPubKey};
+use mnemonic;
+use constants::{GAP_LIMIT, MAX_BLOCK_SIZE, MNEMONIC_WORDS, RELATIVE_LOCK_SECONDS, WALLETS_SESSION_VAR};
 use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal};
+use std::process::{Command, Stdio};
+use std::slice;
+use serialize::serialize;
+use serde_json;
 use mempool::MemPool;
 use error::{Result, Error};
+use coinselect::{Coin, CoinSelector, Strategy};
+use fees::FeeEstimator;
+use history::{self, Direction, HistoryEntry};
+use balance::{self, Balance};
+use message;
+use script::{Op, Script};
+use partial::PartialTX;
+use htlc::{self, Htlc};
+
+const STRATEGIES: &[&str] = &[
+    "largest-first",
+    "smallest-first",
+    "branch-and-bound",
+    "random-improve",
+];
+
+struct SendArgs {
+    from: Address,
+    payments: Vec<TXOutput>,
+    fee: Option<u64>,
+    target: usize,
+    strategy: Option<Strategy>,
+    unconfirmed: bool,
+    replaceable: bool,
+    lock_time: u64,
+    memo: String,
+}
+
+struct SpendScriptArgs {
+    txid: String,
+    vout: usize,
+    unlock: String,
+    from: Option<Address>,
+    to: Address,
+    fee: Option<u64>,
+    target: usize,
+    lock_time: u64,
+    relative_lock_time: u64,
+    memo: String,
+}
+
+struct HTLCArgs {
+    txid: String,
+    vout: usize,
+    preimage: Option<Vec<u8>>,
+    to: Option<Address>,
+    fee: Option<u64>,
+    target: usize,
+}
 
 enum Operation {
     NewChain,
     PrintChain,
-    Balances,
-    Send(String, String, u64),
-    NewWallet,
-    Mine(String)
+    Balances(Option<Address>, usize),
+    Send(SendArgs),
+    NewWallet(bool),
+    Mine(Address),
+    SetStrategy(Address, Strategy),
+    BumpFee(String, u64),
+    EstimateFee(usize),
+    EncryptWallet,
+    ChangePassphrase,
+    Unlock(u64),
+    Lock,
+    GetXPub,
+    DeriveAddress(String, String),
+    Discover(u32),
+    NewMnemonic(usize, String),
+    ShowMnemonic,
+    Restore(String, String, u32),
+    ImportAddress(Address),
+    ImportPubkey(PublicKey),
+    DumpPrivKey(Address),
+    ImportPrivKey(String, bool),
+    ExportWallet(String),
+    ImportWallet(String, bool),
+    History(Option<Address>, Option<String>),
+    SignMessage(Address, String),
+    VerifyMessage(Address, String, String),
+    SpendScript(SpendScriptArgs),
+    GetPubkey(Address),
+    CreateMultisig(usize, Vec<PublicKey>),
+    CreatePSBT(SendArgs, String),
+    SignPSBT(String),
+    CombinePSBT(Vec<String>),
+    FinalizePSBT(String),
+    ExtractPSBT(String),
+    CreateHTLC(SendArgs, Htlc, Option<Vec<u8>>),
+    ClaimHTLC(HTLCArgs),
+    RefundHTLC(HTLCArgs),
+    FindPreimage(String, usize),
+    FindData(Vec<u8>),
+    DisconnectBlock,
+    SetMemPool(Option<usize>, Option<u64>),
 }
 
-fn parse_args() -> Operation {
+fn parse_args() -> Result<Operation> {
 
     let matches = App::new("raicoin")
         .arg(
             Arg::with_name("operation")
                 .help("The type of operation")
-                .possible_values(&["newchain", "printchain", "balance", "send", "newwallet", "mine"])
+                .possible_values(&[
+                    "newchain",
+                    "printchain",
+                    "balance",
+                    "send",
+                    "newwallet",
+                    "mine",
+                    "setstrategy",
+                    "bumpfee",
+                    "estimatefee",
+                    "encryptwallet",
+                    "changepassphrase",
+                    "unlock",
+                    "lock",
+                    "getxpub",
+                    "deriveaddress",
+                    "discover",
+                    "newmnemonic",
+                    "showmnemonic",
+                    "restore",
+                    "importaddress",
+                    "importpubkey",
+                    "dumpprivkey",
+                    "importprivkey",
+                    "exportwallet",
+                    "importwallet",
+                    "history",
+                    "signmessage",
+                    "verifymessage",
+                    "sendtoscript",
+                    "spendscript",
+                    "getpubkey",
+                    "createmultisig",
+                    "createpsbt",
+                    "signpsbt",
+                    "combinepsbt",
+                    "finalizepsbt",
+                    "extractpsbt",
+                    "vest",
+                    "createhtlc",
+                    "claimhtlc",
+                    "refundhtlc",
+                    "findpreimage",
+                    "finddata",
+                    "disconnectblock",
+                    "setmempool",
+                ])
                 .required(true),
         )
         .arg(
             Arg::with_name("from")
-                .help("Address to send from")
-                .required_if("operation", "send")
+                .help("Address to send from, or whose key signs an unlocking script")
+                .required_ifs(&[
+                    ("operation", "send"),
+                    ("operation", "sendtoscript"),
+                    ("operation", "createpsbt"),
+                    ("operation", "vest"),
+                    ("operation", "createhtlc"),
+                ])
                 .long("from")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("to")
                 .help("Address to send to")
-                .required_if("operation", "send")
+                .required_ifs(&[
+                    ("operation", "send"),
+                    ("operation", "spendscript"),
+                    ("operation", "createpsbt"),
+                    ("operation", "vest"),
+                    ("operation", "createhtlc"),
+                ])
                 .long("to")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("amount")
                 .help("Amount to send")
-                .required_if("operation", "send")
+                .required_ifs(&[
+                    ("operation", "send"),
+                    ("operation", "sendtoscript"),
+                    ("operation", "createpsbt"),
+                    ("operation", "vest"),
+                    ("operation", "createhtlc"),
+                ])
                 .long("amount")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("fee")
+                .help("Fee to pay to the miner (estimated if not given)")
+                .required_if("operation", "bumpfee")
+                .long("fee")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target")
+                .help("Number of blocks to confirm within when estimating the fee")
+                .long("target")
+                .takes_value(true)
+                .default_value("6"),
+        )
+        .arg(
+            Arg::with_name("strategy")
+                .help("Coin selection strategy (defaults to the wallet's strategy)")
+                .required_if("operation", "setstrategy")
+                .long("strategy")
+                .possible_values(STRATEGIES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unconfirmed")
+                .help("Allow spending the wallet's own unconfirmed change")
+                .long("unconfirmed"),
+        )
+        .arg(
+            Arg::with_name("replaceable")
+                .help("Allow the transaction to be replaced by one paying a higher fee")
+                .long("replaceable"),
+        )
+        .arg(
+            Arg::with_name("txid")
+                .help("ID of a transaction")
+                .required_ifs(&[
+                    ("operation", "bumpfee"),
+                    ("operation", "spendscript"),
+                    ("operation", "claimhtlc"),
+                    ("operation", "refundhtlc"),
+                    ("operation", "findpreimage"),
+                ])
+                .long("txid")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .help("Number of seconds to keep the wallet unlocked")
+                .long("timeout")
+                .takes_value(true)
+                .default_value("300"),
+        )
+        .arg(
+            Arg::with_name("xpub")
+                .help("Extended public key to derive addresses from")
+                .required_if("operation", "deriveaddress")
+                .long("xpub")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("path")
+                .help("Derivation path relative to the extended public key, e.g. 0/5")
+                .required_if("operation", "deriveaddress")
+                .long("path")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gaplimit")
+                .help("Number of unused addresses in a row after which to stop searching")
+                .long("gaplimit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mnemonic")
+                .help("Mnemonic phrase to restore the wallet from")
+                .required_if("operation", "restore")
+                .long("mnemonic")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seedpassphrase")
+                .help("Optional passphrase protecting the mnemonic phrase")
+                .long("seedpassphrase")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("words")
+                .help("Number of words in a new mnemonic phrase")
+                .long("words")
+                .possible_values(&["12", "15", "18", "21", "24"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("address")
+                .help("Address of a wallet")
+                .required_ifs(&[
+                    ("operation", "setstrategy"),
+                    ("operation", "importaddress"),
+                    ("operation", "dumpprivkey"),
+                    ("operation", "signmessage"),
+                    ("operation", "verifymessage"),
+                    ("operation", "getpubkey"),
+                ])
+                .long("address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pubkey")
+                .help("Hex public key to watch")
+                .required_if("operation", "importpubkey")
+                .long("pubkey")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pubkeys")
+                .help("Comma-separated hex public keys of a multisig address")
+                .required_if("operation", "createmultisig")
+                .long("pubkeys")
+                .takes_value(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::with_name("required")
+                .help("Number of signatures needed to spend from a multisig address")
+                .required_if("operation", "createmultisig")
+                .long("required")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wif")
+                .help("Private key in Wallet Import Format")
+                .required_if("operation", "importprivkey")
+                .long("wif")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("file")
+                .help("File to export the wallet to or import it from, or of a partially signed transaction")
+                .required_ifs(&[
+                    ("operation", "exportwallet"),
+                    ("operation", "importwallet"),
+                    ("operation", "createpsbt"),
+                    ("operation", "signpsbt"),
+                    ("operation", "combinepsbt"),
+                    ("operation", "finalizepsbt"),
+                    ("operation", "extractpsbt"),
+                ])
+                .long("file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("rescan")
+                .help("Search the blockchain for coins paying the imported keys")
+                .long("rescan"),
+        )
+        .arg(
+            Arg::with_name("message")
+                .help("Message to sign or verify")
+                .required_ifs(&[
+                    ("operation", "signmessage"),
+                    ("operation", "verifymessage"),
+                ])
+                .long("message")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("signature")
+                .help("Base64 signature of the message")
+                .required_if("operation", "verifymessage")
+                .long("signature")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("vout")
+                .help("Index of the output to spend in the transaction")
+                .required_ifs(&[
+                    ("operation", "spendscript"),
+                    ("operation", "claimhtlc"),
+                    ("operation", "refundhtlc"),
+                    ("operation", "findpreimage"),
+                ])
+                .long("vout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("script")
+                .help("Script to lock the coins with, e.g. \"SHA256 0x<hash> EQUAL\"")
+                .required_if("operation", "sendtoscript")
+                .long("script")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unlock")
+                .help("Script unlocking the output, where SIG and PUBKEY stand for the signature and public key of --from")
+                .required_if("operation", "spendscript")
+                .long("unlock")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("locktime")
+                .help("Block height, or time in seconds since the epoch, before which the transaction cannot be mined")
+                .long("locktime")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("relativelock")
+                .help("Number of blocks, or seconds if followed by s, after the spent output confirmed before the transaction can be mined")
+                .long("relativelock")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("until")
+                .help("Block height, or time in seconds since the epoch, before which the payment cannot be spent, the first vesting tranche, or when an HTLC can be refunded")
+                .required_ifs(&[
+                    ("operation", "vest"),
+                    ("operation", "createhtlc"),
+                ])
+                .long("until")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .help("Number of blocks, or seconds, between vesting tranches")
+                .required_if("operation", "vest")
+                .long("interval")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tranches")
+                .help("Number of vesting tranches to split the amount into")
+                .required_if("operation", "vest")
+                .long("tranches")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hash")
+                .help("Hex SHA-256 hash locking an HTLC (a new secret is made if not given)")
+                .long("hash")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data")
+                .help("Hex data to carry in an unspendable output, such as a document hash, or to search for")
+                .required_if("operation", "finddata")
+                .long("data")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memo")
+                .help("Reference to attach to the payment, or to search the history for")
+                .long("memo")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("preimage")
+                .help("Hex secret whose hash locks an HTLC")
+                .required_if("operation", "claimhtlc")
+                .long("preimage")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("schnorr")
+                .help("Receive coins on an address spent with Schnorr signatures")
+                .long("schnorr"),
+        )
+        .arg(
+            Arg::with_name("minconf")
+                .help("Number of confirmations before coins count as confirmed")
+                .long("minconf")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("maxsize")
+                .help("Largest total size of the mempool transactions, in bytes")
+                .long("maxsize")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("expiry")
+                .help("Number of seconds a transaction may stay in the mempool")
+                .long("expiry")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("rewardto")
                 .help("The address to send the block reward to")
@@ -59,78 +508,832 @@ fn parse_args() -> Operation {
     let operation = matches.value_of("operation").unwrap();
 
 
-    if operation == "printchain" {
+    let operation = if operation == "printchain" {
         Operation::PrintChain
     } else if operation == "newchain" {
         Operation::NewChain
     } else if operation == "balance" {
-        Operation::Balances
-    } else if operation == "send" {
-        let from = matches.value_of("from").unwrap();
-        let to = matches.value_of("to").unwrap();
+        let min_confirmations = matches.value_of("minconf").unwrap().parse::<usize>().expect(
+            "Minimum confirmations must be a positive integer",
+        );
+        Operation::Balances(parse_wallet_address(&matches)?, min_confirmations)
+    } else if operation == "send" || operation == "sendtoscript" || operation == "createpsbt"
+        || operation == "vest" || operation == "createhtlc"
+    {
+        let from: Address = matches.value_of("from").unwrap().parse()?;
         let amount = matches.value_of("amount").unwrap().parse::<u64>().expect(
             "Amount must be a positive integer",
         );
-        Operation::Send(from.to_owned(), to.to_owned(), amount)
+        let until = matches.value_of("until").map(|until| {
+            until.parse::<u64>().expect("Lock time must be a positive integer")
+        });
+        let mut contract = None;
+        let mut payments = if operation == "sendtoscript" {
+            vec![TXOutput::with_script(amount, matches.value_of("script").unwrap().parse()?)]
+        } else {
+            let to = matches.value_of("to").unwrap().parse()?;
+            if operation == "vest" {
+                let interval = matches.value_of("interval").unwrap().parse::<u64>().expect(
+                    "Interval must be a positive integer",
+                );
+                let tranches = matches.value_of("tranches").unwrap().parse::<u64>().ok()
+                    .filter(|&tranches| tranches > 0)
+                    .expect("Tranches must be a positive integer");
+                vesting_payments(amount, &to, until.unwrap(), interval, tranches)?
+            } else if operation == "createhtlc" {
+                // Without a hash, lock the contract with a new secret
+                let (payment_hash, preimage) = match matches.value_of("hash") {
+                    Some(hash) => (hash.from_hex().map_err(|_| Error::InvalidHTLC)?, None),
+                    None => {
+                        let preimage = htlc::new_preimage();
+                        (htlc::payment_hash(&preimage), Some(preimage))
+                    }
+                };
+                let htlc = Htlc::new(payment_hash, to, from.clone(), until.unwrap())?;
+                let payment = TXOutput::with_script(amount, htlc.script());
+                contract = Some((htlc, preimage));
+                vec![payment]
+            } else if let Some(until) = until {
+                vec![TXOutput::time_locked(amount, &to, until)?]
+            } else {
+                vec![TXOutput::new(amount, &to)]
+            }
+        };
+        if matches.is_present("data") {
+            payments.push(TXOutput::data(parse_data(&matches)?)?);
+        }
+        let fee = matches.value_of("fee").map(|fee| {
+            fee.parse::<u64>().expect("Fee must be a positive integer")
+        });
+        let target = parse_target(&matches);
+        let strategy = matches.value_of("strategy").map(|s| s.parse().unwrap());
+        let unconfirmed = matches.is_present("unconfirmed");
+        let replaceable = matches.is_present("replaceable");
+        let lock_time = parse_lock_time(&matches);
+        let memo = matches.value_of("memo").unwrap_or("").to_owned();
+        let args = SendArgs {
+            from,
+            payments,
+            fee,
+            target,
+            strategy,
+            unconfirmed,
+            replaceable,
+            lock_time,
+            memo,
+        };
+        if operation == "createpsbt" {
+            Operation::CreatePSBT(args, matches.value_of("file").unwrap().to_owned())
+        } else if let Some((htlc, preimage)) = contract {
+            Operation::CreateHTLC(args, htlc, preimage)
+        } else {
+            Operation::Send(args)
+        }
     } else if operation == "newwallet" {
-        Operation::NewWallet
+        Operation::NewWallet(matches.is_present("schnorr"))
     } else if operation == "mine" {
-        let reward_to = matches.value_of("rewardto").unwrap();
-        Operation::Mine(reward_to.to_owned())
-    }
-    else {
+        let reward_to = matches.value_of("rewardto").unwrap().parse()?;
+        Operation::Mine(reward_to)
+    } else if operation == "setstrategy" {
+        let address = matches.value_of("address").unwrap().parse()?;
+        let strategy = matches.value_of("strategy").unwrap().parse().unwrap();
+        Operation::SetStrategy(address, strategy)
+    } else if operation == "bumpfee" {
+        let txid = matches.value_of("txid").unwrap();
+        let fee = matches.value_of("fee").unwrap().parse::<u64>().expect(
+            "Fee must be a positive integer",
+        );
+        Operation::BumpFee(txid.to_owned(), fee)
+    } else if operation == "estimatefee" {
+        Operation::EstimateFee(parse_target(&matches))
+    } else if operation == "encryptwallet" {
+        Operation::EncryptWallet
+    } else if operation == "changepassphrase" {
+        Operation::ChangePassphrase
+    } else if operation == "unlock" {
+        let timeout = matches.value_of("timeout").unwrap().parse::<u64>().expect(
+            "Timeout must be a positive integer",
+        );
+        Operation::Unlock(timeout)
+    } else if operation == "lock" {
+        Operation::Lock
+    } else if operation == "getxpub" {
+        Operation::GetXPub
+    } else if operation == "deriveaddress" {
+        let xpub = matches.value_of("xpub").unwrap();
+        let path = matches.value_of("path").unwrap();
+        Operation::DeriveAddress(xpub.to_owned(), path.to_owned())
+    } else if operation == "discover" {
+        Operation::Discover(parse_gap_limit(&matches))
+    } else if operation == "newmnemonic" {
+        let words = matches.value_of("words").map_or(MNEMONIC_WORDS, |words| {
+            words.parse::<usize>().unwrap()
+        });
+        let passphrase = matches.value_of("seedpassphrase").unwrap_or("");
+        Operation::NewMnemonic(words, passphrase.to_owned())
+    } else if operation == "showmnemonic" {
+        Operation::ShowMnemonic
+    } else if operation == "restore" {
+        let mnemonic = matches.value_of("mnemonic").unwrap();
+        let passphrase = matches.value_of("seedpassphrase").unwrap_or("");
+        Operation::Restore(mnemonic.to_owned(), passphrase.to_owned(), parse_gap_limit(&matches))
+    } else if operation == "importaddress" {
+        let address = matches.value_of("address").unwrap().parse()?;
+        Operation::ImportAddress(address)
+    } else if operation == "importpubkey" {
+        let pubkey = matches.value_of("pubkey").unwrap().from_hex().map_err(|_| Error::InvalidPublicKey)?;
+        let pubkey = PublicKey::from_slice(&Secp256k1::new(), &pubkey).map_err(|_| Error::InvalidPublicKey)?;
+        Operation::ImportPubkey(pubkey)
+    } else if operation == "dumpprivkey" {
+        let address = matches.value_of("address").unwrap().parse()?;
+        Operation::DumpPrivKey(address)
+    } else if operation == "importprivkey" {
+        let wif = matches.value_of("wif").unwrap();
+        Operation::ImportPrivKey(wif.to_owned(), matches.is_present("rescan"))
+    } else if operation == "exportwallet" {
+        let file = matches.value_of("file").unwrap();
+        Operation::ExportWallet(file.to_owned())
+    } else if operation == "importwallet" {
+        let file = matches.value_of("file").unwrap();
+        Operation::ImportWallet(file.to_owned(), matches.is_present("rescan"))
+    } else if operation == "history" {
+        let memo = matches.value_of("memo").map(|memo| memo.to_owned());
+        Operation::History(parse_wallet_address(&matches)?, memo)
+    } else if operation == "signmessage" {
+        let address = matches.value_of("address").unwrap().parse()?;
+        let message = matches.value_of("message").unwrap();
+        Operation::SignMessage(address, message.to_owned())
+    } else if operation == "verifymessage" {
+        let address = matches.value_of("address").unwrap().parse()?;
+        let message = matches.value_of("message").unwrap();
+        let signature = matches.value_of("signature").unwrap();
+        Operation::VerifyMessage(address, message.to_owned(), signature.to_owned())
+    } else if operation == "spendscript" {
+        let txid = matches.value_of("txid").unwrap();
+        let vout = parse_vout(&matches);
+        let unlock = matches.value_of("unlock").unwrap();
+        let from = parse_from(&matches)?;
+        let to = matches.value_of("to").unwrap().parse()?;
+        let fee = matches.value_of("fee").map(|fee| {
+            fee.parse::<u64>().expect("Fee must be a positive integer")
+        });
+        Operation::SpendScript(SpendScriptArgs {
+            txid: txid.to_owned(),
+            vout,
+            unlock: unlock.to_owned(),
+            from,
+            to,
+            fee,
+            target: parse_target(&matches),
+            lock_time: parse_lock_time(&matches),
+            relative_lock_time: parse_relative_lock_time(&matches),
+            memo: matches.value_of("memo").unwrap_or("").to_owned(),
+        })
+    } else if operation == "getpubkey" {
+        let address = matches.value_of("address").unwrap().parse()?;
+        Operation::GetPubkey(address)
+    } else if operation == "createmultisig" {
+        let required = matches.value_of("required").unwrap().parse::<usize>().expect(
+            "Required signatures must be a positive integer",
+        );
+        let secp = Secp256k1::new();
+        let mut pubkeys = Vec::new();
+        for pubkey in matches.values_of("pubkeys").unwrap() {
+            let pubkey = pubkey.from_hex().map_err(|_| Error::InvalidPublicKey)?;
+            pubkeys.push(PublicKey::from_slice(&secp, &pubkey).map_err(|_| Error::InvalidPublicKey)?);
+        }
+        Operation::CreateMultisig(required, pubkeys)
+    } else if operation == "signpsbt" {
+        Operation::SignPSBT(matches.value_of("file").unwrap().to_owned())
+    } else if operation == "combinepsbt" {
+        let files = matches.values_of("file").unwrap().map(|file| file.to_owned()).collect();
+        Operation::CombinePSBT(files)
+    } else if operation == "finalizepsbt" {
+        Operation::FinalizePSBT(matches.value_of("file").unwrap().to_owned())
+    } else if operation == "extractpsbt" {
+        Operation::ExtractPSBT(matches.value_of("file").unwrap().to_owned())
+    } else if operation == "claimhtlc" || operation == "refundhtlc" {
+        let preimage = match matches.value_of("preimage") {
+            Some(preimage) if operation == "claimhtlc" => {
+                Some(preimage.from_hex().map_err(|_| Error::InvalidPreimage)?)
+            }
+            _ => None,
+        };
+        let args = HTLCArgs {
+            txid: matches.value_of("txid").unwrap().to_owned(),
+            vout: parse_vout(&matches),
+            preimage,
+            to: parse_to(&matches)?,
+            fee: matches.value_of("fee").map(|fee| {
+                fee.parse::<u64>().expect("Fee must be a positive integer")
+            }),
+            target: parse_target(&matches),
+        };
+        if operation == "claimhtlc" {
+            Operation::ClaimHTLC(args)
+        } else {
+            Operation::RefundHTLC(args)
+        }
+    } else if operation == "findpreimage" {
+        let txid = matches.value_of("txid").unwrap();
+        Operation::FindPreimage(txid.to_owned(), parse_vout(&matches))
+    } else if operation == "finddata" {
+        Operation::FindData(parse_data(&matches)?)
+    } else if operation == "disconnectblock" {
+        Operation::DisconnectBlock
+    } else if operation == "setmempool" {
+        let max_size = matches.value_of("maxsize").map(|max_size| {
+            max_size.parse::<usize>().expect("Maximum size must be a positive integer")
+        });
+        let expiry = matches.value_of("expiry").map(|expiry| {
+            expiry.parse::<u64>().expect("Expiry must be a positive integer")
+        });
+        Operation::SetMemPool(max_size, expiry)
+    } else {
         panic!("Unknown argument {}", operation)
-     }
+    };
+
+    Ok(operation)
+}
+
+fn parse_target(matches: &ArgMatches) -> usize {
+    matches.value_of("target").unwrap().parse::<usize>().expect(
+        "Target must be a positive integer",
+    )
+}
+
+fn parse_vout(matches: &ArgMatches) -> usize {
+    matches.value_of("vout").unwrap().parse::<usize>().expect(
+        "Output index must be a positive integer",
+    )
+}
+
+fn parse_data(matches: &ArgMatches) -> Result<Vec<u8>> {
+    matches.value_of("data").unwrap().from_hex().map_err(|_| Error::InvalidData)
+}
+
+fn parse_lock_time(matches: &ArgMatches) -> u64 {
+    matches.value_of("locktime").map_or(0, |lock_time| {
+        lock_time.parse::<u64>().expect("Lock time must be a positive integer")
+    })
+}
+
+// A number of blocks, or of seconds if suffixed with s
+fn parse_relative_lock_time(matches: &ArgMatches) -> u64 {
+    matches.value_of("relativelock").map_or(0, |lock| {
+        let (value, flag) = match lock.trim_end_matches('s') {
+            seconds if seconds.len() < lock.len() => (seconds, RELATIVE_LOCK_SECONDS),
+            blocks => (blocks, 0),
+        };
+        let value = value.parse::<u64>().expect("Relative lock time must be a positive integer");
+        if value >= RELATIVE_LOCK_SECONDS {
+            panic!("Relative lock time must be less than {}", RELATIVE_LOCK_SECONDS);
+        }
+        value | flag
+    })
+}
+
+fn parse_wallet_address(matches: &ArgMatches) -> Result<Option<Address>> {
+    match matches.value_of("address") {
+        Some(address) => Ok(Some(address.parse()?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_from(matches: &ArgMatches) -> Result<Option<Address>> {
+    match matches.value_of("from") {
+        Some(address) => Ok(Some(address.parse()?)),
+        None => Ok(None),
+    }
 }
 
-fn get_balances(bc: &Blockchain) -> HashMap<String, u64> {
+fn parse_to(matches: &ArgMatches) -> Result<Option<Address>> {
+    match matches.value_of("to") {
+        Some(address) => Ok(Some(address.parse()?)),
+        None => Ok(None),
+    }
+}
+
+fn parse_gap_limit(matches: &ArgMatches) -> u32 {
+    matches.value_of("gaplimit").map_or(GAP_LIMIT, |gap_limit| {
+        gap_limit.parse::<u32>().expect("Gap limit must be a positive integer")
+    })
+}
+
+fn get_balances(bc: &Blockchain) -> HashMap<Address, u64> {
     utxo::find(bc)
-        .iter()
-        .flat_map(|(_, entries)| entries.iter())
+        .values()
+        .flat_map(|entries| entries.iter())
         .fold(HashMap::new(), |mut acc, entry| {
-            let pubkey_hash = entry.output.pubkey_hash.clone();
-            let value = entry.output.value;
-            *acc.entry(pubkey_hash).or_insert(0) += value;
+            if let Ok(address) = entry.output.address() {
+                *acc.entry(address).or_insert(0) += entry.output.value;
+            }
             acc
         })
 }
 
-fn send(mp: &mut MemPool, bc: &Blockchain, from: &str, to:&str, amount: u64) -> Result<()> {
+// The total balance of the addresses matching `filter`
+fn total_balance<F: Fn(&Address) -> bool>(bc: &Blockchain, filter: F) -> u64 {
+    get_balances(bc)
+        .iter()
+        .filter(|&(address, _)| filter(address))
+        .map(|(_, balance)| balance)
+        .sum()
+}
+
+// Whether `address` is a key, watch-only or multisig address of the wallets
+fn is_known(wallets: &Wallets, address: &Address) -> bool {
+    wallets.get(address).is_some()
+        || wallets.is_watch_only(address)
+        || wallets.redeem_script(address).is_some()
+}
+
+// The given wallet address, or every address in the wallets
+fn wallet_addresses(wallets: &Wallets, address: Option<Address>) -> Result<Vec<Address>> {
+    match address {
+        Some(address) => {
+            if !is_known(wallets, &address) {
+                return Err(Error::NoWalletForAddress);
+            }
+            Ok(vec![address])
+        }
+        None => Ok(wallets.addresses()),
+    }
+}
+
+fn print_balance(name: &str, balance: &Balance) {
+    println!(
+        "{}: {} confirmed, {} unconfirmed, {} immature, {} locked",
+        name, balance.confirmed, balance.unconfirmed, balance.immature, balance.locked
+    );
+}
+
+fn print_history_entry(entry: &HistoryEntry) {
+    println!("==============================\n");
+    println!("txid: {}", entry.txid);
+    let counterparties: Vec<String> = entry.counterparties
+        .iter()
+        .map(|address| address.to_string())
+        .collect();
+    match entry.direction {
+        Direction::Received => println!("{} {} from {}", entry.direction, entry.amount, counterparties.join(", ")),
+        Direction::Sent => println!("{} {} to {}", entry.direction, entry.amount, counterparties.join(", ")),
+        Direction::ToSelf | Direction::Mined => println!("{} {}", entry.direction, entry.amount),
+    }
+    if let Some(fee) = entry.fee {
+        println!("fee: {}", fee);
+    }
+    if !entry.memo.is_empty() {
+        println!("memo: {}", entry.memo);
+    }
+    match entry.height {
+        Some(height) => println!(
+            "block {}, {} confirmations, time {}\n",
+            height, entry.confirmations, entry.timestamp
+        ),
+        None => println!("pending in the mempool since {}\n", entry.timestamp),
+    }
+}
+
+// Reads a passphrase from standard input, without echoing it when typed at
+// a terminal
+fn read_passphrase(prompt: &str) -> String {
+    let stdin = io::stdin();
+    let terminal = stdin.is_terminal();
+    eprint!("{}: ", prompt);
+    if terminal {
+        set_echo(false);
+    }
+    let mut passphrase = String::new();
+    let read = stdin.lock().read_line(&mut passphrase);
+    if terminal {
+        set_echo(true);
+        eprintln!();
+    }
+    read.expect("Could not read the passphrase");
+
+    passphrase.trim_end_matches(&['\r', '\n'][..]).to_owned()
+}
+
+fn set_echo(on: bool) {
+    let _ = Command::new("stty")
+        .arg(if on { "echo" } else { "-echo" })
+        .stdin(Stdio::inherit())
+        .status();
+}
+
+// Reads a new passphrase twice, so that a typo does not lock the wallet
+fn read_new_passphrase() -> Result<String> {
+    let passphrase = read_passphrase("New passphrase");
+    if read_passphrase("Repeat the new passphrase") != passphrase {
+        return Err(Error::PassphraseMismatch);
+    }
+    Ok(passphrase)
+}
+
+// Starts tracking a new mempool transaction for fee estimation, and stops
+// tracking those it replaced or evicted
+fn track(fees: &mut FeeEstimator, mp: &mut MemPool, bc: &Blockchain, tx: &TX) {
+    untrack_dropped(fees, mp);
+    if let TX::Standard(ref stx) = *tx {
+        if let Ok(fee_rate) = stx.fee_rate(&mp.view(bc)) {
+            fees.track(&tx.id(), fee_rate, bc.height());
+        }
+    }
+}
+
+// Stops tracking the transactions that left the mempool without confirming
+fn untrack_dropped(fees: &mut FeeEstimator, mp: &mut MemPool) {
+    for txid in mp.take_dropped() {
+        fees.untrack(&txid);
+    }
+}
+
+// Splits `amount` into `tranches` payments to `to`, the first spendable from
+// `start` and each following one `interval` later, with the remainder in
+// the last
+fn vesting_payments(
+    amount: u64,
+    to: &Address,
+    start: u64,
+    interval: u64,
+    tranches: u64,
+) -> Result<Vec<TXOutput>> {
+    if amount < tranches {
+        return Err(Error::InsufficientFunds);
+    }
+    (0..tranches)
+        .map(|i| {
+            let value = if i == tranches - 1 {
+                amount - amount / tranches * (tranches - 1)
+            } else {
+                amount / tranches
+            };
+            let lock_time = i.checked_mul(interval)
+                .and_then(|offset| start.checked_add(offset))
+                .ok_or(Error::LockTimeOutOfRange)?;
+            TXOutput::time_locked(value, to, lock_time)
+        })
+        .collect()
+}
+
+// The outputs a transaction sending `args` may spend
+fn spendable_utxo(mp: &MemPool, bc: &Blockchain, args: &SendArgs) -> UTXO {
+    let mut utxo = if args.unconfirmed {
+        mp.utxo_with_change(bc, &args.from)
+    } else {
+        mp.utxo(bc)
+    };
+    utxo::remove_immature(bc, &mut utxo);
+    utxo
+}
+
+// The fee at the estimated fee rate on a transaction of `size` bytes
+fn estimated_fee(fees: &FeeEstimator, target: usize, size: usize) -> u64 {
+    let fee_rate = fees.estimate_fee(target).unwrap_or(0.0);
+    (fee_rate * size as f64 / 1000.0).ceil() as u64
+}
+
+fn send(
+    mp: &mut MemPool,
+    bc: &Blockchain,
+    fees: &mut FeeEstimator,
+    args: &SendArgs,
+) -> Result<String> {
 
     let wallets = Wallets::open();
-    let from_wallet = wallets.get(from).ok_or(Error::NoWalletForAddress)?;
+    let from_wallet = wallets.signer(&args.from)?;
+    let selector: &dyn CoinSelector = match args.strategy {
+        Some(ref s) => s,
+        None => &from_wallet.strategy,
+    };
 
-    let tx = TX::Standard(StandardTX::new(bc, from_wallet, to, amount)?);
-    mp.push(bc, tx)?;
+    let utxo = spendable_utxo(mp, bc, args);
 
-    Ok(())
+    let mut options = TXOptions {
+        fee: 0,
+        replaceable: args.replaceable,
+        lock_time: args.lock_time,
+        memo: args.memo.clone(),
+    };
+    options.fee = match args.fee {
+        Some(fee) => fee,
+        None => {
+            // Pay the estimated fee rate on the size of the transaction
+            // without a fee
+            let unfunded = StandardTX::new(&utxo, from_wallet, &args.from, &args.payments, selector, &options)?;
+            estimated_fee(fees, args.target, unfunded.size())
+        }
+    };
+
+    let tx = TX::Standard(StandardTX::new(&utxo, from_wallet, &args.from, &args.payments, selector, &options)?);
+    let txid = tx.id();
+    mp.push(bc, tx.clone())?;
+    track(fees, mp, bc, &tx);
+
+    Ok(txid)
+}
+
+fn bump_fee(
+    mp: &mut MemPool,
+    bc: &Blockchain,
+    fees: &mut FeeEstimator,
+    txid: &str,
+    fee: u64,
+) -> Result<String> {
+    let wallets = Wallets::open();
+    let tx = match mp.get(txid) {
+        Some(TX::Standard(tx)) => tx.clone(),
+        _ => return Err(Error::TransactionMissing),
+    };
+    let input = tx.inputs.first().ok_or(Error::TransactionMissing)?;
+    let wallet = wallets
+        .find_by_public_key(&input.pubkey.ok_or(Error::NoWalletForAddress)?)
+        .ok_or(Error::NoWalletForAddress)?;
+
+    let replacement = TX::Standard(tx.bump_fee(&mp.view(bc), wallet, fee)?);
+    let replacement_id = replacement.id();
+    mp.push(bc, replacement.clone())?;
+    track(fees, mp, bc, &replacement);
+
+    Ok(replacement_id)
+}
+
+// Fills in an unlocking script, where SIG stands for the wallet's signature
+// of `digest` and PUBKEY for its public key
+fn unlock_script(unlock: &str, wallet: Option<&Wallet>, digest: &[u8]) -> Result<Script> {
+    let mut ops = Vec::new();
+    for op in unlock.split_whitespace() {
+        ops.push(match op {
+            "SIG" => Op::Push(wallet.ok_or(Error::NoWalletForAddress)?.sign_digest(digest)?),
+            "PUBKEY" => {
+                let public_key = wallet.ok_or(Error::NoWalletForAddress)?.public_key;
+                Op::Push(serialize_public_key(&public_key))
+            }
+            _ => op.parse()?,
+        });
+    }
+    Ok(Script::new(ops))
+}
+
+// The unspent output `vout` of `txid`
+fn find_coin(mp: &MemPool, bc: &Blockchain, txid: &str, vout: usize) -> Result<Coin> {
+    let output = mp.utxo(bc)
+        .get(txid)
+        .and_then(|entries| entries.iter().find(|entry| entry.vout == vout))
+        .map(|entry| entry.output.clone())
+        .ok_or(Error::NoTXOutput)?;
+
+    Ok(Coin {
+        txid: txid.to_owned(),
+        vout,
+        output,
+    })
 }
 
-fn mine(mp: &mut MemPool, bc: &mut Blockchain, reward_to: &str) -> Result<()> {
-    let reward = TX::Coinbase(CoinbaseTX::new(reward_to.to_owned()));
+// Sends the transaction made by `build` for a given fee, paying `fee` or
+// else the estimated fee rate
+fn send_built<F>(
+    mp: &mut MemPool,
+    bc: &Blockchain,
+    fees: &mut FeeEstimator,
+    fee: Option<u64>,
+    target: usize,
+    build: F,
+) -> Result<String>
+where
+    F: Fn(u64) -> Result<StandardTX>,
+{
+    let fee = match fee {
+        Some(fee) => fee,
+        None => estimated_fee(fees, target, build(0)?.size()),
+    };
+
+    let tx = TX::Standard(build(fee)?);
+    let txid = tx.id();
+    mp.push(bc, tx.clone())?;
+    track(fees, mp, bc, &tx);
+
+    Ok(txid)
+}
+
+fn spend_script(
+    mp: &mut MemPool,
+    bc: &Blockchain,
+    fees: &mut FeeEstimator,
+    args: &SpendScriptArgs,
+) -> Result<String> {
+    let wallets = Wallets::open();
+    let wallet = match args.from {
+        Some(ref from) => Some(wallets.signer(from)?),
+        None => None,
+    };
+    let coin = find_coin(mp, bc, &args.txid, args.vout)?;
+
+    // Send the whole output, less the fee
+    send_built(mp, bc, fees, args.fee, args.target, |fee| {
+        let value = coin.output.value.checked_sub(fee).ok_or(Error::InsufficientFunds)?;
+        let outputs = vec![TXOutput::new(value, &args.to)];
+        let options = TXOptions {
+            fee,
+            replaceable: false,
+            lock_time: args.lock_time,
+            memo: args.memo.clone(),
+        };
+        StandardTX::spend_script(&coin, outputs, &options, args.relative_lock_time, |digest| {
+            unlock_script(&args.unlock, wallet, digest)
+        })
+    })
+}
+
+// Spends an HTLC output: claims it with the preimage if one is given, or
+// else refunds it to the sender once the contract has timed out
+fn spend_htlc(
+    mp: &mut MemPool,
+    bc: &Blockchain,
+    fees: &mut FeeEstimator,
+    args: &HTLCArgs,
+) -> Result<String> {
+    let coin = find_coin(mp, bc, &args.txid, args.vout)?;
+    let htlc = coin.output.script.as_ref().and_then(Htlc::parse).ok_or(Error::InvalidHTLC)?;
+
+    let wallets = Wallets::open();
+    let (wallet, lock_time) = match args.preimage {
+        Some(_) => (wallets.signer(&htlc.recipient)?, 0),
+        None => (wallets.signer(&htlc.sender)?, htlc.timeout),
+    };
+    let to = args.to.clone().unwrap_or_else(|| wallet.address());
+    let public_key = serialize_public_key(&wallet.public_key);
+
+    // Send the whole output, less the fee
+    send_built(mp, bc, fees, args.fee, args.target, |fee| {
+        let value = coin.output.value.checked_sub(fee).ok_or(Error::InsufficientFunds)?;
+        let outputs = vec![TXOutput::new(value, &to)];
+        let options = TXOptions {
+            fee,
+            replaceable: false,
+            lock_time,
+            memo: String::new(),
+        };
+        StandardTX::spend_script(&coin, outputs, &options, 0, |digest| {
+            let signature = wallet.sign_digest(digest)?;
+            match args.preimage {
+                Some(ref preimage) => htlc.claim_script(signature, public_key.clone(), preimage),
+                None => Ok(Htlc::refund_script(signature, public_key.clone())),
+            }
+        })
+    })
+}
+
+// The preimage revealed by the transaction, in the mempool or the
+// blockchain, that claimed HTLC output `vout` of `txid`
+fn find_preimage(mp: &MemPool, bc: &Blockchain, txid: &str, vout: usize) -> Result<Vec<u8>> {
+    let tx = mp.get(txid).or_else(|| bc.find_transaction(txid)).ok_or(Error::TransactionMissing)?;
+    let htlc = tx.outputs()
+        .get(vout)
+        .and_then(|output| output.script.as_ref())
+        .and_then(Htlc::parse)
+        .ok_or(Error::InvalidHTLC)?;
+
+    let spenders = mp.transactions()
+        .chain(bc.iter().flat_map(|(_, block)| block.transactions.iter()));
+    for tx in spenders {
+        if let TX::Standard(ref stx) = *tx {
+            let claim = stx.inputs
+                .iter()
+                .filter(|input| input.txid == txid && input.vout == vout)
+                .filter_map(|input| input.unlock.as_ref())
+                .find_map(|unlock| htlc.preimage(unlock));
+            if let Some(preimage) = claim {
+                return Ok(preimage);
+            }
+        }
+    }
+
+    Err(Error::HTLCNotClaimed)
+}
+
+fn read_partial(file: &str) -> Result<PartialTX> {
+    let contents = fs::read_to_string(file).map_err(|_| Error::InvalidPartialTX)?;
+    serde_json::from_str(&contents).map_err(|_| Error::InvalidPartialTX)
+}
+
+fn write_partial(file: &str, partial: &PartialTX) -> Result<()> {
+    fs::write(file, serialize(partial)).map_err(|_| Error::InvalidPartialTX)
+}
+
+// Writes an unsigned transaction paying from an address of the wallet,
+// which may be watch-only or multisig, for the holders of its keys to sign
+fn create_psbt(
+    mp: &MemPool,
+    bc: &Blockchain,
+    fees: &FeeEstimator,
+    args: &SendArgs,
+    file: &str,
+) -> Result<()> {
+    let wallets = Wallets::open();
+    if !is_known(&wallets, &args.from) {
+        return Err(Error::NoWalletForAddress);
+    }
+    let redeem_script = wallets.redeem_script(&args.from).cloned();
+    let default_strategy = Strategy::default();
+    let selector: &dyn CoinSelector = match (args.strategy.as_ref(), wallets.get(&args.from)) {
+        (Some(strategy), _) => strategy,
+        (None, Some(wallet)) => &wallet.strategy,
+        (None, None) => &default_strategy,
+    };
+    let utxo = spendable_utxo(mp, bc, args);
 
-    let transactions = match mp.pop() {
-        Some(tx) => vec![tx, reward],
-        None => vec![reward]
+    let build = |fee: u64| {
+        let options = TXOptions {
+            fee,
+            replaceable: args.replaceable,
+            lock_time: args.lock_time,
+            memo: args.memo.clone(),
+        };
+        let (coins, outputs) = StandardTX::select_coins(
+            &utxo, &args.from, &args.payments, selector, &options,
+        )?;
+        let coins = coins.into_iter().map(|coin| (coin, redeem_script.clone())).collect();
+        Ok(PartialTX::new(coins, outputs, &options))
     };
+    let fee = match args.fee {
+        Some(fee) => fee,
+        None => estimated_fee(fees, args.target, build(0)?.tx.size()),
+    };
+
+    write_partial(file, &build(fee)?)
+}
+
+// Sends the transaction of a finalized partially signed transaction
+fn extract_psbt(
+    mp: &mut MemPool,
+    bc: &Blockchain,
+    fees: &mut FeeEstimator,
+    file: &str,
+) -> Result<String> {
+    let tx = TX::Standard(read_partial(file)?.extract()?);
+    let txid = tx.id();
+    mp.push(bc, tx.clone())?;
+    track(fees, mp, bc, &tx);
+
+    Ok(txid)
+}
+
+// The transactions carrying `payload` in a data output, with the heights
+// of the blocks holding them, or `None` for those still in the mempool
+fn find_data(mp: &MemPool, bc: &Blockchain, payload: &[u8]) -> Vec<(String, Option<usize>)> {
+    let carries = |tx: &TX| tx.outputs().iter().any(|output| output.payload() == Some(payload));
+
+    let mut found: Vec<(String, Option<usize>)> = mp.transactions()
+        .filter(|tx| carries(tx))
+        .map(|tx| (tx.id(), None))
+        .collect();
+    for (depth, (_, block)) in bc.iter().enumerate() {
+        for tx in block.transactions.iter().filter(|tx| carries(tx)) {
+            found.push((tx.id(), Some(bc.height() - depth)));
+        }
+    }
+    found
+}
+
+fn mine(
+    mp: &mut MemPool,
+    bc: &mut Blockchain,
+    fees: &mut FeeEstimator,
+    reward_to: &Address,
+) -> Result<()> {
+    let (mut transactions, fees_paid) = mp.select(bc, MAX_BLOCK_SIZE);
+    transactions.push(TX::Coinbase(CoinbaseTX::new(reward_to, fees_paid)));
     let prev_block_hash = bc.last_block_hash.clone();
 
     let block = Block::mine(transactions, prev_block_hash);
     bc.add_block(block)?;
+    let block = bc.get_block(&bc.last_block_hash).unwrap();
+    mp.block_connected(block);
+    untrack_dropped(fees, mp);
+    fees.block_connected(block, bc.height());
+
+    Ok(())
+}
+
+// Removes the last block, returning its transactions to the mempool
+fn disconnect_block(mp: &mut MemPool, bc: &mut Blockchain, fees: &mut FeeEstimator) -> Result<()> {
+    let block = bc.disconnect_tip()?;
+    mp.block_disconnected(bc, &block);
+    untrack_dropped(fees, mp);
 
     Ok(())
 }
 
 pub fn run() -> Result<()> {
-    let operation = parse_args();
+    let operation = parse_args()?;
 
     match operation {
         Operation::NewChain => {
             let mut wallets = Wallets::open();
-            let wallet = Wallet::new();
-            let address = hash_public_key(&wallet.public_key);
+            let address = wallets.new_address()?;
             Blockchain::new(&address)?;
-            wallets.add(wallet);
             println!("Created a new blockchain");
         }
         Operation::PrintChain => {
@@ -140,32 +1343,308 @@ pub fn run() -> Result<()> {
                 println!("hash: {}\ncontents: {}\n", hash, block);
             }
         }
-        Operation::Balances => {
+        Operation::Balances(address, min_confirmations) => {
             let bc = Blockchain::open()?;
-            for (address, balance) in get_balances(&bc) {
-                println!("{}: {}", address, balance);
+            let mp = MemPool::open();
+            let wallets = Wallets::open();
+            let addresses = wallet_addresses(&wallets, address)?;
+            for address in addresses.iter() {
+                let balance = balance::balance(&bc, &mp, slice::from_ref(address), min_confirmations);
+                if balance.total() == 0 {
+                    continue;
+                }
+                if wallets.is_watch_only(address) {
+                    print_balance(&format!("{} (watch-only)", address), &balance);
+                } else if wallets.redeem_script(address).is_some() {
+                    print_balance(&format!("{} (multisig)", address), &balance);
+                } else {
+                    print_balance(&address.to_string(), &balance);
+                }
             }
+            print_balance("total", &balance::balance(&bc, &mp, &addresses, min_confirmations));
         }
-        Operation::Send(from, to, amount) => {
-            let mut bc = Blockchain::open()?;
+        Operation::Send(args) => {
+            let bc = Blockchain::open()?;
             let mut mp = MemPool::open();
-            send(&mut mp, &mut bc, &from, &to, amount)?;
-            println!("Sent transaction to the mempool")
+            let mut fees = FeeEstimator::open();
+            let txid = send(&mut mp, &bc, &mut fees, &args)?;
+            println!("Sent transaction {} to the mempool", txid)
         }
-        Operation::NewWallet => {
+        Operation::NewWallet(schnorr) => {
             let mut wallets = Wallets::open();
-            let wallet = Wallet::new();
-            let address = hash_public_key(&wallet.public_key);
+            let mut address = wallets.new_address()?;
+            if schnorr {
+                let wallet = wallets.get_mut(&address).unwrap();
+                wallet.schnorr = true;
+                address = wallet.address();
+            }
             println!("Created wallet:\n{}", address);
-            wallets.add(wallet);
-
         }
         Operation::Mine(reward_to) => {
             let bc = &mut Blockchain::open()?;
             let mp = &mut MemPool::open();
-            mine(mp, bc, &reward_to)?;
+            let fees = &mut FeeEstimator::open();
+            mine(mp, bc, fees, &reward_to)?;
             println!("Mined a block and added it to the blockchain");
         }
+        Operation::SetStrategy(address, strategy) => {
+            let mut wallets = Wallets::open();
+            wallets.get_mut(&address).ok_or(Error::NoWalletForAddress)?.strategy = strategy;
+            println!("Set the coin selection strategy of {}", address);
+        }
+        Operation::BumpFee(txid, fee) => {
+            let bc = Blockchain::open()?;
+            let mut mp = MemPool::open();
+            let mut fees = FeeEstimator::open();
+            let replacement_id = bump_fee(&mut mp, &bc, &mut fees, &txid, fee)?;
+            println!("Replaced transaction {} with {}", txid, replacement_id);
+        }
+        Operation::EstimateFee(target) => {
+            let fees = FeeEstimator::open();
+            match fees.estimate_fee(target) {
+                Some(fee_rate) => println!("{} coins per kilobyte", fee_rate),
+                None => println!("Not enough data to estimate a fee"),
+            }
+        }
+        Operation::EncryptWallet => {
+            let mut wallets = Wallets::open();
+            let passphrase = read_new_passphrase()?;
+            wallets.encrypt(&passphrase)?;
+            println!("Encrypted the wallet file");
+        }
+        Operation::ChangePassphrase => {
+            let mut wallets = Wallets::open();
+            let old = read_passphrase("Passphrase");
+            let new = read_new_passphrase()?;
+            wallets.change_passphrase(&old, &new)?;
+            println!("Changed the wallet passphrase");
+        }
+        Operation::Unlock(timeout) => {
+            let mut wallets = Wallets::open();
+            let token = wallets.unlock(&read_passphrase("Passphrase"), timeout)?;
+            println!("Unlocked the wallet for {} seconds. Set the session token to use it:", timeout);
+            println!("export {}={}", WALLETS_SESSION_VAR, token);
+        }
+        Operation::Lock => {
+            let mut wallets = Wallets::open();
+            wallets.lock();
+            println!("Locked the wallet");
+        }
+        Operation::GetXPub => {
+            let wallets = Wallets::open();
+            println!("{}", wallets.account_xpub()?.encode());
+        }
+        Operation::DeriveAddress(xpub, path) => {
+            let xpub = ExtendedPubKey::decode(&xpub)?;
+            let key = xpub.derive_path(&hd::parse_path(&path)?)?;
+            println!("{}", Address::from_public_key(&key.public_key, HashType::Hash160));
+        }
+        Operation::Discover(gap_limit) => {
+            let bc = Blockchain::open()?;
+            let mut wallets = Wallets::open();
+            let found = wallets.discover(&bc, gap_limit)?;
+            println!("Found {} used addresses", found);
+        }
+        Operation::NewMnemonic(words, passphrase) => {
+            let mut wallets = Wallets::open();
+            let phrase = mnemonic::generate(words)?;
+            wallets.set_mnemonic(&phrase, &passphrase)?;
+            println!("Write down this mnemonic phrase to restore the wallet:\n{}", phrase);
+        }
+        Operation::ShowMnemonic => {
+            let wallets = Wallets::open();
+            println!("{}", wallets.mnemonic()?);
+        }
+        Operation::Restore(phrase, passphrase, gap_limit) => {
+            let bc = Blockchain::open()?;
+            let mut wallets = Wallets::open();
+            wallets.set_mnemonic(&phrase, &passphrase)?;
+            let found = wallets.discover(&bc, gap_limit)?;
+            let balance = total_balance(&bc, |address| wallets.get(address).is_some());
+            println!("Restored {} used addresses with a balance of {}", found, balance);
+        }
+        Operation::ImportAddress(address) => {
+            let mut wallets = Wallets::open();
+            wallets.import_address(address.clone())?;
+            println!("Watching {}", address);
+        }
+        Operation::ImportPubkey(pubkey) => {
+            let mut wallets = Wallets::open();
+            let address = wallets.import_public_key(pubkey)?;
+            println!("Watching {}", address);
+        }
+        Operation::DumpPrivKey(address) => {
+            let wallets = Wallets::open();
+            println!("{}", wallets.signer(&address)?.to_wif()?);
+        }
+        Operation::ImportPrivKey(wif, rescan) => {
+            let mut wallets = Wallets::open();
+            let address = wallets.import_key(Wallet::from_wif(&wif)?)?;
+            println!("Imported {}", address);
+            if rescan {
+                let bc = Blockchain::open()?;
+                let balance = total_balance(&bc, |a| *a == address);
+                println!("Rescanned the blockchain: {} has a balance of {}", address, balance);
+            }
+        }
+        Operation::ExportWallet(file) => {
+            let wallets = Wallets::open();
+            let export = wallets.export()?;
+            export.write(&file).map_err(|_| Error::InvalidWalletExport)?;
+            println!("Exported the wallet to {}, readable by you only", file);
+            println!("Warning: the keys in it are not encrypted, even if the wallet is. Keep it offline and delete it once imported");
+        }
+        Operation::History(address, memo) => {
+            let bc = Blockchain::open()?;
+            let mp = MemPool::open();
+            let wallets = Wallets::open();
+            let addresses = wallet_addresses(&wallets, address)?;
+            let entries = history::history(&bc, &mp, &addresses)
+                .into_iter()
+                .filter(|entry| memo.as_ref().is_none_or(|memo| entry.memo.contains(memo.as_str())));
+            for entry in entries {
+                print_history_entry(&entry);
+            }
+        }
+        Operation::SignMessage(address, msg) => {
+            let wallets = Wallets::open();
+            println!("{}", message::sign(wallets.signer(&address)?, &msg)?);
+        }
+        Operation::VerifyMessage(address, msg, signature) => {
+            if message::verify(&address, &msg, &signature)? {
+                println!("The signature is valid");
+            } else {
+                println!("The signature is not valid for this address and message");
+            }
+        }
+        Operation::SpendScript(args) => {
+            let bc = Blockchain::open()?;
+            let mut mp = MemPool::open();
+            let mut fees = FeeEstimator::open();
+            let txid = spend_script(&mut mp, &bc, &mut fees, &args)?;
+            println!("Sent transaction {} to the mempool", txid)
+        }
+        Operation::GetPubkey(address) => {
+            let wallets = Wallets::open();
+            let wallet = wallets.get(&address).ok_or(Error::NoWalletForAddress)?;
+            println!("{}", serialize_public_key(&wallet.public_key).to_hex());
+        }
+        Operation::CreateMultisig(required, pubkeys) => {
+            let mut wallets = Wallets::open();
+            let address = wallets.add_multisig(required, &pubkeys)?;
+            println!("Created a {}-of-{} multisig address:\n{}", required, pubkeys.len(), address);
+        }
+        Operation::CreatePSBT(args, file) => {
+            let bc = Blockchain::open()?;
+            let mp = MemPool::open();
+            let fees = FeeEstimator::open();
+            create_psbt(&mp, &bc, &fees, &args, &file)?;
+            println!("Wrote the unsigned transaction to {}", file);
+        }
+        Operation::SignPSBT(file) => {
+            let wallets = Wallets::open();
+            let mut partial = read_partial(&file)?;
+            let added = partial.sign(&wallets)?;
+            write_partial(&file, &partial)?;
+            println!("Added {} signatures to {}", added, file);
+        }
+        Operation::CombinePSBT(files) => {
+            let mut partial = read_partial(&files[0])?;
+            for file in files[1..].iter() {
+                partial.combine(read_partial(file)?)?;
+            }
+            write_partial(&files[0], &partial)?;
+            println!("Combined the signatures into {}", files[0]);
+        }
+        Operation::FinalizePSBT(file) => {
+            let mut partial = read_partial(&file)?;
+            partial.finalize()?;
+            write_partial(&file, &partial)?;
+            println!("Finalized the transaction in {}", file);
+        }
+        Operation::ExtractPSBT(file) => {
+            let bc = Blockchain::open()?;
+            let mut mp = MemPool::open();
+            let mut fees = FeeEstimator::open();
+            let txid = extract_psbt(&mut mp, &bc, &mut fees, &file)?;
+            println!("Sent transaction {} to the mempool", txid)
+        }
+        Operation::CreateHTLC(args, htlc, preimage) => {
+            let bc = Blockchain::open()?;
+            let mut mp = MemPool::open();
+            let mut fees = FeeEstimator::open();
+            let txid = send(&mut mp, &bc, &mut fees, &args)?;
+            println!("Sent transaction {} to the mempool", txid);
+            println!("HTLC output: {} vout 0", txid);
+            println!("hash: {}", htlc.payment_hash.to_hex());
+            if let Some(preimage) = preimage {
+                println!("preimage: {}", preimage.to_hex());
+            }
+        }
+        Operation::ClaimHTLC(args) | Operation::RefundHTLC(args) => {
+            let bc = Blockchain::open()?;
+            let mut mp = MemPool::open();
+            let mut fees = FeeEstimator::open();
+            let txid = spend_htlc(&mut mp, &bc, &mut fees, &args)?;
+            println!("Sent transaction {} to the mempool", txid)
+        }
+        Operation::FindPreimage(txid, vout) => {
+            let bc = Blockchain::open()?;
+            let mp = MemPool::open();
+            println!("{}", find_preimage(&mp, &bc, &txid, vout)?.to_hex());
+        }
+        Operation::FindData(payload) => {
+            let bc = Blockchain::open()?;
+            let mp = MemPool::open();
+            for (txid, height) in find_data(&mp, &bc, &payload) {
+                match height {
+                    Some(height) => println!("{} in block {}", txid, height),
+                    None => println!("{} in the mempool", txid),
+                }
+            }
+        }
+        Operation::DisconnectBlock => {
+            let bc = &mut Blockchain::open()?;
+            let mp = &mut MemPool::open();
+            let fees = &mut FeeEstimator::open();
+            disconnect_block(mp, bc, fees)?;
+            println!("Disconnected the last block, its transactions are back in the mempool");
+        }
+        Operation::SetMemPool(max_size, expiry) => {
+            let bc = Blockchain::open()?;
+            let mut mp = MemPool::open();
+            let mut fees = FeeEstimator::open();
+            if let Some(max_size) = max_size {
+                mp.max_size = max_size;
+            }
+            if let Some(expiry) = expiry {
+                mp.expiry = expiry;
+            }
+            // Apply the new limits to the transactions already in the mempool
+            mp.expire();
+            mp.trim(&bc);
+            untrack_dropped(&mut fees, &mut mp);
+            println!("The mempool holds at most {} bytes, for {} seconds", mp.max_size, mp.expiry);
+        }
+        Operation::ImportWallet(file, rescan) => {
+            let contents = fs::read_to_string(&file).map_err(|_| Error::InvalidWalletExport)?;
+            let export: Export = serde_json::from_str(&contents).map_err(|_| Error::InvalidWalletExport)?;
+            let mut wallets = Wallets::open();
+            let imported = wallets.import(export)?;
+            println!("Imported {} addresses", imported.len());
+            if rescan {
+                let bc = Blockchain::open()?;
+                let found = match wallets.discover(&bc, GAP_LIMIT) {
+                    Ok(found) => found,
+                    Err(Error::NoHDSeed) => 0,
+                    Err(err) => return Err(err),
+                };
+                let balance = total_balance(&bc, |address| {
+                    wallets.get(address).is_some() || wallets.is_watch_only(address)
+                });
+                println!("Rescanned the blockchain: found {} used HD addresses, the wallet has a balance of {}", found, balance);
+            }
+        }
     }
 
     Ok(())
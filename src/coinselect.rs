@@ -0,0 +1,278 @@
+use transaction::TXOutput;
+use rand::OsRng;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+// Upper bound on the number of branches explored by the branch-and-bound search
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// An unspent output that can be used as an input to a new transaction.
+#[derive(Debug, Clone)]
+pub struct Coin {
+    pub txid: String,
+    pub vout: usize,
+    pub output: TXOutput,
+}
+
+/// Chooses which coins to spend to cover a target amount.
+pub trait CoinSelector {
+    /// Returns a subset of `coins` whose total value is at least `target`, or
+    /// `None` if the strategy cannot find one.
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>>;
+}
+
+/// Spends the largest coins first, minimising the number of inputs.
+pub struct LargestFirst;
+
+/// Spends the smallest coins first, consolidating dust.
+pub struct SmallestFirst;
+
+/// Searches for a set of coins that exactly matches the target, so that no
+/// change output is needed.
+pub struct BranchAndBound;
+
+/// Selects coins at random, then keeps adding random coins while doing so
+/// moves the change closer to the payment amount.
+pub struct RandomImprove;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Strategy {
+    #[default]
+    LargestFirst,
+    SmallestFirst,
+    BranchAndBound,
+    RandomImprove,
+}
+
+pub fn total(coins: &[Coin]) -> u64 {
+    coins.iter().map(|coin| coin.output.value).sum()
+}
+
+fn accumulate<'a, I>(coins: I, target: u64) -> Option<Vec<Coin>>
+where
+    I: Iterator<Item = &'a Coin>,
+{
+    let mut acc_amount = 0;
+    let mut selected = Vec::new();
+
+    for coin in coins {
+        if acc_amount >= target {
+            break;
+        }
+        acc_amount += coin.output.value;
+        selected.push(coin.clone());
+    }
+
+    if acc_amount >= target {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        let mut sorted: Vec<&Coin> = coins.iter().collect();
+        sorted.sort_by_key(|coin| Reverse(coin.output.value));
+        accumulate(sorted.into_iter(), target)
+    }
+}
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        let mut sorted: Vec<&Coin> = coins.iter().collect();
+        sorted.sort_by_key(|coin| coin.output.value);
+        accumulate(sorted.into_iter(), target)
+    }
+}
+
+struct Search<'a> {
+    coins: Vec<&'a Coin>,
+    // remaining[i] is the total value of coins[i..]
+    remaining: Vec<u64>,
+    selected: Vec<usize>,
+    tries: usize,
+}
+
+impl<'a> Search<'a> {
+    fn run(&mut self, index: usize, acc_amount: u64, target: u64) -> bool {
+        self.tries += 1;
+        if acc_amount == target {
+            return true;
+        }
+        if index == self.coins.len()
+            || acc_amount + self.remaining[index] < target
+            || self.tries > BNB_MAX_TRIES
+        {
+            return false;
+        }
+
+        // Branch on including the coin, then on leaving it out
+        let value = self.coins[index].output.value;
+        if acc_amount + value <= target {
+            self.selected.push(index);
+            if self.run(index + 1, acc_amount + value, target) {
+                return true;
+            }
+            self.selected.pop();
+        }
+
+        self.run(index + 1, acc_amount, target)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        let mut sorted: Vec<&Coin> = coins.iter().collect();
+        sorted.sort_by_key(|coin| Reverse(coin.output.value));
+
+        let mut remaining = vec![0; sorted.len() + 1];
+        for i in (0..sorted.len()).rev() {
+            remaining[i] = remaining[i + 1] + sorted[i].output.value;
+        }
+
+        let mut search = Search {
+            coins: sorted,
+            remaining,
+            selected: Vec::new(),
+            tries: 0,
+        };
+
+        if search.run(0, 0, target) {
+            Some(search.selected.iter().map(|&i| search.coins[i].clone()).collect())
+        } else {
+            None
+        }
+    }
+}
+
+impl CoinSelector for RandomImprove {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        let mut shuffled: Vec<&Coin> = coins.iter().collect();
+        OsRng::new().unwrap().shuffle(&mut shuffled);
+
+        // Random selection until the target is covered
+        let mut selected = accumulate(shuffled.iter().cloned(), target)?;
+        let mut acc_amount = total(&selected);
+
+        // Improve: aim for change roughly equal to the payment, without
+        // exceeding three times the target
+        let ideal = target.saturating_mul(2);
+        let limit = target.saturating_mul(3);
+        for coin in shuffled.into_iter().skip(selected.len()) {
+            let new_amount = acc_amount + coin.output.value;
+            let closer = new_amount.abs_diff(ideal) < acc_amount.abs_diff(ideal);
+            if !closer || new_amount > limit {
+                break;
+            }
+            acc_amount = new_amount;
+            selected.push(coin.clone());
+        }
+
+        Some(selected)
+    }
+}
+
+impl CoinSelector for Strategy {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        match *self {
+            Strategy::LargestFirst => LargestFirst.select(coins, target),
+            Strategy::SmallestFirst => SmallestFirst.select(coins, target),
+            Strategy::BranchAndBound => BranchAndBound.select(coins, target),
+            Strategy::RandomImprove => RandomImprove.select(coins, target),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<Strategy, ()> {
+        match s {
+            "largest-first" => Ok(Strategy::LargestFirst),
+            "smallest-first" => Ok(Strategy::SmallestFirst),
+            "branch-and-bound" => Ok(Strategy::BranchAndBound),
+            "random-improve" => Ok(Strategy::RandomImprove),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address::Address;
+    use wallet::HashType;
+
+    const FEE: u64 = 2;
+
+    fn coins(values: &[u64]) -> Vec<Coin> {
+        let to = Address::from_pubkey_hash(&"00".repeat(20), HashType::Hash160).unwrap();
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, &value)| Coin {
+                txid: "00".repeat(32),
+                vout,
+                output: TXOutput::new(value, &to),
+            })
+            .collect()
+    }
+
+    fn values(coins: &[Coin]) -> Vec<u64> {
+        coins.iter().map(|coin| coin.output.value).collect()
+    }
+
+    // Selects coins paying `amount` plus the fee, checking they cover both
+    fn select(selector: &dyn CoinSelector, coins: &[Coin], amount: u64) -> Vec<Coin> {
+        let selected = selector.select(coins, amount + FEE).unwrap();
+        assert!(total(&selected) >= amount + FEE);
+        selected
+    }
+
+    #[test]
+    fn largest_first_spends_the_largest_coins() {
+        let selected = select(&LargestFirst, &coins(&[5, 10, 20, 50]), 55);
+        assert_eq!(values(&selected), vec![50, 20]);
+    }
+
+    #[test]
+    fn smallest_first_spends_the_smallest_coins() {
+        let selected = select(&SmallestFirst, &coins(&[5, 10, 20, 50]), 30);
+        assert_eq!(values(&selected), vec![5, 10, 20]);
+    }
+
+    #[test]
+    fn branch_and_bound_matches_the_target_exactly() {
+        let selected = select(&BranchAndBound, &coins(&[5, 10, 20, 50]), 33);
+        assert_eq!(total(&selected), 33 + FEE);
+        assert_eq!(values(&selected), vec![20, 10, 5]);
+    }
+
+    #[test]
+    fn branch_and_bound_fails_without_an_exact_match() {
+        assert!(BranchAndBound.select(&coins(&[5, 10, 20, 50]), 34 + FEE).is_none());
+    }
+
+    #[test]
+    fn random_improve_covers_the_target() {
+        let coins = coins(&[1, 2, 3, 5, 8, 13, 21, 34, 55, 89]);
+        for _ in 0..100 {
+            select(&RandomImprove, &coins, 40);
+        }
+    }
+
+    #[test]
+    fn every_strategy_fails_on_insufficient_funds() {
+        let coins = coins(&[5, 10, 20]);
+        for strategy in &[
+            Strategy::LargestFirst,
+            Strategy::SmallestFirst,
+            Strategy::BranchAndBound,
+            Strategy::RandomImprove,
+        ] {
+            assert!(strategy.select(&coins, 34 + FEE).is_none());
+        }
+    }
+}
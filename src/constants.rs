@@ -1,4 +1,64 @@
 pub const DIFFICULTY: usize = 12;
-pub const BLOCKCHAIN: &'static str = "blockchain.json";
-pub const WALLETS: &'static str = "wallets.json";
-pub const MEMPOOL: &'static str = "mempool.json";
+pub const BLOCKCHAIN: &str = "blockchain.json";
+pub const WALLETS: &str = "wallets.json";
+pub const WALLETS_SESSION: &str = "wallets.unlock";
+// The environment variable holding the token that decrypts the session
+pub const WALLETS_SESSION_VAR: &str = "RAICOIN_SESSION";
+pub const MEMPOOL: &str = "mempool.json";
+pub const FEES: &str = "fees.json";
+// The version bytes that start addresses on this network, for each way of
+// hashing the public key
+pub const SHA256_ADDRESS_VERSION: u8 = 0x3c;
+pub const HASH160_ADDRESS_VERSION: u8 = 0x3d;
+// The version byte of addresses paying to the hash of a script
+pub const SCRIPT_ADDRESS_VERSION: u8 = 0x3f;
+// The version bytes of addresses whose coins are spent with Schnorr
+// signatures
+pub const SCHNORR_SHA256_ADDRESS_VERSION: u8 = 0x40;
+pub const SCHNORR_HASH160_ADDRESS_VERSION: u8 = 0x41;
+// The version of outputs spent with Schnorr signatures, rather than ECDSA
+pub const SCHNORR_OUTPUT_VERSION: u8 = 1;
+// The version byte that starts secret keys in Wallet Import Format
+pub const WIF_VERSION: u8 = 0xbc;
+pub const GAP_LIMIT: u32 = 20;
+pub const MNEMONIC_WORDS: usize = 12;
+// The number of confirmations a block reward needs before it can be spent
+pub const COINBASE_MATURITY: usize = 100;
+// Lock times below this are block heights, and times in seconds since the
+// epoch from it on
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+// Set in a relative lock time counted in seconds rather than blocks
+pub const RELATIVE_LOCK_SECONDS: u64 = 1 << 31;
+pub const MAX_ANCESTORS: usize = 25;
+pub const MAX_DESCENDANTS: usize = 25;
+pub const MEMPOOL_MAX_SIZE: usize = 1_000_000;
+pub const MEMPOOL_EXPIRY: u64 = 14 * 24 * 60 * 60;
+// The number of latest blocks whose median timestamp a new block may not
+// precede
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// The most seconds a block timestamp may be ahead of the clock
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+// The most bytes of mempool transactions mined into one block
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+// Resource limits of the script interpreter: non-push operations per
+// script, script size in bytes, bytes per stack item, items on the stack
+// and bytes per number operand
+pub const MAX_SCRIPT_OPS: usize = 201;
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+pub const MAX_STACK_SIZE: usize = 1000;
+pub const MAX_SCRIPT_NUM_SIZE: usize = 8;
+// The most signature checks spending an output may run, counting a
+// CHECKMULTISIG as one per public key
+pub const MAX_SCRIPT_SIGOPS: usize = 20;
+// The most public keys a multisig script may check signatures against
+pub const MAX_MULTISIG_KEYS: usize = 20;
+// The size in bytes of the secret whose hash locks a new HTLC
+pub const PREIMAGE_SIZE: usize = 32;
+// The most bytes a data output may carry
+pub const MAX_DATA_SIZE: usize = 80;
+// The most bytes a transaction memo may hold
+pub const MAX_MEMO_SIZE: usize = 256;
+// The fewest inputs each thread verifies when validating a block, below
+// which starting another thread costs more than it saves
+pub const MIN_INPUTS_PER_THREAD: usize = 8;
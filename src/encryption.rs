@@ -0,0 +1,120 @@
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::scrypt::{scrypt, ScryptParams};
+use rand::OsRng;
+use rand::Rng;
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+/// The scrypt cost parameters used to derive a key from a passphrase.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams { log_n: 15, r: 8, p: 1 }
+    }
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    OsRng::new().unwrap().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Derives a symmetric key from a passphrase with scrypt.
+pub fn derive_key(passphrase: &str, salt: &[u8], params: &KdfParams) -> Vec<u8> {
+    let mut key = vec![0; KEY_SIZE];
+    let scrypt_params = ScryptParams::new(params.log_n, params.r, params.p);
+    scrypt(passphrase.as_bytes(), salt, &scrypt_params, &mut key);
+    key
+}
+
+/// Encrypts and authenticates `plaintext` and `aad` with ChaCha20-Poly1305,
+/// returning the nonce, ciphertext and tag.
+pub fn encrypt(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let nonce = random_bytes(NONCE_SIZE);
+    let mut ciphertext = vec![0; plaintext.len()];
+    let mut tag = vec![0; TAG_SIZE];
+    ChaCha20Poly1305::new(key, &nonce, aad).encrypt(plaintext, &mut ciphertext, &mut tag);
+
+    let mut sealed = nonce;
+    sealed.extend(ciphertext);
+    sealed.extend(tag);
+    sealed
+}
+
+/// Reverses `encrypt`, returning `None` if the key is wrong or the data has
+/// been tampered with.
+pub fn decrypt(key: &[u8], sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_SIZE + TAG_SIZE {
+        return None;
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+    let mut plaintext = vec![0; ciphertext.len()];
+    if ChaCha20Poly1305::new(key, nonce, aad).decrypt(ciphertext, &mut plaintext, tag) {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KDF: KdfParams = KdfParams { log_n: 4, r: 8, p: 1 };
+
+    #[test]
+    fn encrypted_data_round_trips() {
+        let key = random_bytes(KEY_SIZE);
+        let sealed = encrypt(&key, b"secret", b"label");
+        assert_eq!(sealed.len(), NONCE_SIZE + 6 + TAG_SIZE);
+        assert_eq!(decrypt(&key, &sealed, b"label").unwrap(), b"secret");
+        assert_eq!(decrypt(&key, &encrypt(&key, b"", b""), b"").unwrap(), b"");
+    }
+
+    #[test]
+    fn each_encryption_uses_a_new_nonce() {
+        let key = random_bytes(KEY_SIZE);
+        assert_ne!(encrypt(&key, b"secret", b""), encrypt(&key, b"secret", b""));
+    }
+
+    #[test]
+    fn decryption_fails_with_the_wrong_key_or_data() {
+        let key = random_bytes(KEY_SIZE);
+        let sealed = encrypt(&key, b"secret", b"label");
+        assert!(decrypt(&random_bytes(KEY_SIZE), &sealed, b"label").is_none());
+        assert!(decrypt(&key, &sealed, b"other").is_none());
+        assert!(decrypt(&key, &sealed[..sealed.len() - 1], b"label").is_none());
+        assert!(decrypt(&key, &sealed[..NONCE_SIZE + TAG_SIZE - 1], b"label").is_none());
+    }
+
+    #[test]
+    fn decryption_fails_on_any_tampered_byte() {
+        let key = random_bytes(KEY_SIZE);
+        let sealed = encrypt(&key, b"secret", b"");
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x80;
+            assert!(decrypt(&key, &tampered, b"").is_none(), "byte {} was not authenticated", i);
+        }
+    }
+
+    #[test]
+    fn keys_derive_from_the_passphrase_and_salt() {
+        let key = derive_key("passphrase", b"salt", &TEST_KDF);
+        assert_eq!(key.len(), KEY_SIZE);
+        assert_eq!(derive_key("passphrase", b"salt", &TEST_KDF), key);
+        assert_ne!(derive_key("passphrase", b"pepper", &TEST_KDF), key);
+        assert_ne!(derive_key("passphrase2", b"salt", &TEST_KDF), key);
+    }
+}
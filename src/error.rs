@@ -1,5 +1,7 @@
+use std::fmt;
 use std::result;
 
+#[derive(Debug)]
 pub enum Error {
     InsufficientFunds,
     TransactionMissing,
@@ -9,23 +11,123 @@ pub enum Error {
     InvalidNonce,
     TooManyCoinbase,
     NoWalletForAddress,
+    CoinSelectionFailed,
+    TooManyAncestors,
+    TooManyDescendants,
+    OutputsExceedInputs,
+    ConflictingTransaction,
+    InsufficientReplacementFee,
+    OutputAlreadySpent,
+    MemPoolFull,
+    WalletLocked,
+    WalletEncrypted,
+    WalletNotEncrypted,
+    IncorrectPassphrase,
+    InvalidExtendedKey,
+    InvalidDerivationPath,
+    HardenedDerivation,
+    NoHDSeed,
+    HDSeedExists,
+    InvalidMnemonic,
+    NoMnemonic,
+    InvalidAddress,
+    PubkeyHashMismatch,
+    WatchOnlyAddress,
+    AddressExists,
+    InvalidPublicKey,
+    InvalidPrivateKey,
+    InvalidWalletExport,
+    ImmatureCoinbase,
+    InvalidSignature,
+    InvalidScript,
+    ScriptFailed,
+    ScriptLimitExceeded,
+    ScriptHashMismatch,
+    InvalidMultisig,
+    MissingSignatures,
+    PartialTXMismatch,
+    InvalidPartialTX,
+    TransactionLocked,
+    InvalidHTLC,
+    InvalidPreimage,
+    HTLCNotClaimed,
+    DataTooLarge,
+    InvalidData,
+    MemoTooLong,
+    InvalidTransaction,
+    PassphraseMismatch,
+    GenesisDisconnect,
+    ExcessiveReward,
+    NonstandardDataOutput,
+    InvalidTimestamp,
+    LockTimeOutOfRange,
 }
 
-impl Error {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Error::*;
-        let x = match self {
-            &InsufficientFunds => "insufficient funds",
-            &TransactionMissing => "transaction does not exist",
-            &NoTXOutput => "transaction output does not exist",
-            &PubkeySignatureMismatch => "public key does not match the signature",
-            &InvalidPreviousHash => "previous_block_hash of the block is incorrect",
-            &InvalidNonce => "the block nonce is incorrect",
-            &TooManyCoinbase => "too many coinbase transactions in the block",
-            &NoWalletForAddress => "a wallet does not exist for this address",
+        let x = match *self {
+            InsufficientFunds => "insufficient funds",
+            TransactionMissing => "transaction does not exist",
+            NoTXOutput => "transaction output does not exist",
+            PubkeySignatureMismatch => "public key does not match the signature",
+            InvalidPreviousHash => "previous_block_hash of the block is incorrect",
+            InvalidNonce => "the block nonce is incorrect",
+            TooManyCoinbase => "too many coinbase transactions in the block",
+            NoWalletForAddress => "a wallet does not exist for this address",
+            CoinSelectionFailed => "the coin selection strategy found no suitable inputs",
+            TooManyAncestors => "too many unconfirmed ancestors in the mempool",
+            TooManyDescendants => "too many unconfirmed descendants in the mempool",
+            OutputsExceedInputs => "transaction outputs are worth more than its inputs",
+            ConflictingTransaction => "transaction spends an output already spent in the mempool",
+            InsufficientReplacementFee => "replacement transaction does not pay a high enough fee",
+            OutputAlreadySpent => "transaction spends an output already spent in the blockchain",
+            MemPoolFull => "the mempool is full and the transaction fee rate is too low",
+            WalletLocked => "the wallet is locked, unlock it and set the session token first",
+            WalletEncrypted => "the wallet is already encrypted",
+            WalletNotEncrypted => "the wallet is not encrypted",
+            IncorrectPassphrase => "the passphrase is incorrect",
+            InvalidExtendedKey => "the extended key is invalid",
+            InvalidDerivationPath => "the derivation path is invalid",
+            HardenedDerivation => "hardened keys cannot be derived from a public key",
+            NoHDSeed => "the wallet has no HD seed",
+            HDSeedExists => "the wallet already has an HD seed",
+            InvalidMnemonic => "the mnemonic phrase is invalid",
+            NoMnemonic => "the HD seed was not made from a mnemonic phrase",
+            InvalidAddress => "the address is invalid",
+            PubkeyHashMismatch => "the public key does not match the output it spends",
+            WatchOnlyAddress => "the address is watch-only, the wallet cannot sign for it",
+            AddressExists => "the address is already in the wallet",
+            InvalidPublicKey => "the public key is invalid",
+            InvalidPrivateKey => "the private key is invalid",
+            InvalidWalletExport => "the wallet export file could not be read",
+            ImmatureCoinbase => "the transaction spends a block reward that has not matured",
+            InvalidSignature => "the signature is malformed",
+            InvalidScript => "the script is invalid",
+            ScriptFailed => "the unlocking script does not satisfy the locking script",
+            ScriptLimitExceeded => "the script exceeds the resource limits",
+            ScriptHashMismatch => "the script does not match the output it spends",
+            InvalidMultisig => "a multisig address needs between 1 and 20 keys, at least as many as the signatures required",
+            MissingSignatures => "the transaction does not have enough signatures yet",
+            PartialTXMismatch => "the partially signed transactions are not of the same transaction",
+            InvalidPartialTX => "the partially signed transaction file could not be read",
+            TransactionLocked => "the transaction is time-locked until a later block",
+            InvalidHTLC => "the output is not a hash time-locked contract",
+            InvalidPreimage => "the preimage does not match the hash of the contract",
+            HTLCNotClaimed => "the contract has not been claimed",
+            DataTooLarge => "a data output can carry at most 80 bytes",
+            InvalidData => "the data is not valid hex",
+            MemoTooLong => "a memo can be at most 256 bytes",
+            InvalidTransaction => "the transaction values overflow",
+            PassphraseMismatch => "the passphrases do not match",
+            GenesisDisconnect => "the genesis block cannot be disconnected",
+            ExcessiveReward => "the coinbase pays more than the block reward and fees",
+            NonstandardDataOutput => "an unspendable output may only carry a single push of data",
+            InvalidTimestamp => "the block timestamp is before the median time past or too far in the future",
+            LockTimeOutOfRange => "the lock time is too large for a script",
         };
 
-        format!("Error: {}", x)
+        write!(f, "Error: {}", x)
     }
 }
 
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use serialize::{serialize, deserialize};
+use block::Block;
+use constants::FEES;
+
+// The longest confirmation target, in blocks
+pub const MAX_TARGET: usize = 25;
+
+// Fee rate buckets, in coins per kilobyte, grow geometrically from the
+// first non-zero bucket
+const MIN_BUCKET: f64 = 0.1;
+const BUCKET_SPACING: f64 = 1.5;
+const NUM_BUCKETS: usize = 35;
+
+// How much old observations are discounted at each new block
+const DECAY: f64 = 0.998;
+// The fraction of transactions in a bucket that must have confirmed within
+// the target for the bucket's fee rate to be recommended
+const SUCCESS_THRESHOLD: f64 = 0.85;
+// The least amount of (decayed) data needed to judge a bucket
+const MIN_SAMPLES: f64 = 0.5;
+
+#[derive(Serialize, Deserialize)]
+struct Bucket {
+    fee_rate: f64,
+    total: f64,
+    // confirmed[i] counts the transactions that confirmed within i + 1 blocks
+    confirmed: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Tracked {
+    bucket: usize,
+    height: usize,
+}
+
+/// Estimates the fee rate needed for a transaction to confirm within a
+/// number of blocks, from how long the mempool's transactions took to
+/// confirm.
+#[derive(Serialize, Deserialize)]
+pub struct FeeEstimator {
+    buckets: Vec<Bucket>,
+    tracked: HashMap<String, Tracked>,
+}
+
+impl FeeEstimator {
+    fn new() -> FeeEstimator {
+        let buckets = (0..NUM_BUCKETS)
+            .map(|i| {
+                let fee_rate = if i == 0 {
+                    0.0
+                } else {
+                    MIN_BUCKET * BUCKET_SPACING.powi(i as i32 - 1)
+                };
+                Bucket {
+                    fee_rate,
+                    total: 0.0,
+                    confirmed: vec![0.0; MAX_TARGET],
+                }
+            })
+            .collect();
+
+        FeeEstimator {
+            buckets,
+            tracked: HashMap::new(),
+        }
+    }
+
+    fn save(&self) {
+        let mut file = File::create(FEES).unwrap();
+        file.write_all(serialize(self).as_bytes()).unwrap()
+    }
+
+    pub fn open() -> FeeEstimator {
+        let file = File::open(FEES);
+
+        match file {
+            Ok(mut f) => {
+                let mut contents = String::new();
+                f.read_to_string(&mut contents).unwrap();
+
+                deserialize(&contents)
+            }
+            Err(_) => FeeEstimator::new(),
+        }
+    }
+
+    fn bucket(&self, fee_rate: f64) -> usize {
+        self.buckets
+            .iter()
+            .rposition(|bucket| bucket.fee_rate <= fee_rate)
+            .unwrap_or(0)
+    }
+
+    fn record(&mut self, bucket: usize, blocks: Option<usize>) {
+        let bucket = &mut self.buckets[bucket];
+        bucket.total += 1.0;
+        if let Some(blocks) = blocks {
+            for confirmed in bucket.confirmed.iter_mut().skip(blocks.max(1) - 1) {
+                *confirmed += 1.0;
+            }
+        }
+    }
+
+    /// Starts tracking a transaction that entered the mempool when the
+    /// blockchain was at `height`, paying `fee_rate` coins per kilobyte.
+    pub fn track(&mut self, txid: &str, fee_rate: f64, height: usize) {
+        let bucket = self.bucket(fee_rate);
+        self.tracked.insert(txid.to_owned(), Tracked { bucket, height });
+    }
+
+    /// Stops tracking a transaction that left the mempool without
+    /// confirming, so that it does not count as a failure to confirm.
+    pub fn untrack(&mut self, txid: &str) {
+        self.tracked.remove(txid);
+    }
+
+    /// Records how long the tracked transactions in `block`, now at
+    /// `height`, took to confirm.
+    pub fn block_connected(&mut self, block: &Block, height: usize) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.total *= DECAY;
+            for confirmed in bucket.confirmed.iter_mut() {
+                *confirmed *= DECAY;
+            }
+        }
+
+        for tx in block.transactions.iter() {
+            if let Some(tracked) = self.tracked.remove(&tx.id()) {
+                let blocks = height.saturating_sub(tracked.height);
+                self.record(tracked.bucket, Some(blocks));
+            }
+        }
+
+        // Transactions still unconfirmed after the longest target count as
+        // failures in every target
+        let expired: Vec<String> = self.tracked
+            .iter()
+            .filter(|&(_, tracked)| height.saturating_sub(tracked.height) >= MAX_TARGET)
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        for txid in expired {
+            let tracked = self.tracked.remove(&txid).unwrap();
+            self.record(tracked.bucket, None);
+        }
+    }
+
+    /// The lowest fee rate, in coins per kilobyte, at which transactions
+    /// have reliably confirmed within `target_blocks`, or `None` if there is
+    /// not enough data.
+    pub fn estimate_fee(&self, target_blocks: usize) -> Option<f64> {
+        let target = target_blocks.clamp(1, MAX_TARGET);
+
+        // Walk down from the highest fee rate until a bucket fails
+        let mut estimate = None;
+        for bucket in self.buckets.iter().rev() {
+            if bucket.total < MIN_SAMPLES {
+                continue;
+            }
+            if bucket.confirmed[target - 1] / bucket.total < SUCCESS_THRESHOLD {
+                break;
+            }
+            estimate = Some(bucket.fee_rate);
+        }
+
+        estimate
+    }
+}
+
+impl Drop for FeeEstimator {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
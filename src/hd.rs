@@ -0,0 +1,388 @@
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha512;
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::Secp256k1;
+use base58;
+use error::{Error, Result};
+use wallet::{hash160, serialize_public_key};
+
+/// Child indexes at or above this are hardened.
+pub const HARDENED: u32 = 1 << 31;
+
+// The HMAC key and version bytes are those of BIP32, so its test vectors
+// hold and extended keys move between tools. Keys of a seed shared with
+// Bitcoin are kept apart by the coin type of the derivation path instead
+const MASTER_KEY: &[u8] = b"Bitcoin seed";
+const XPRV_VERSION: [u8; 4] = [0x04, 0x88, 0xad, 0xe4];
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const ENCODED_SIZE: usize = 78;
+
+/// A private key that can derive a tree of child keys.
+#[derive(Clone)]
+pub struct ExtendedPrivKey {
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub secret_key: SecretKey,
+}
+
+/// A public key that can derive the public keys of its non-hardened
+/// children, without knowing any secret keys.
+#[derive(Clone)]
+pub struct ExtendedPubKey {
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub public_key: PublicKey,
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut hmac = Hmac::new(Sha512::new(), key);
+    hmac.input(data);
+    let result = hmac.result();
+    let code = result.code();
+
+    let mut left = [0; 32];
+    let mut right = [0; 32];
+    left.copy_from_slice(&code[..32]);
+    right.copy_from_slice(&code[32..]);
+    (left, right)
+}
+
+fn fingerprint(public_key: &PublicKey) -> [u8; 4] {
+    let hash = hash160(&serialize_public_key(public_key));
+
+    let mut fingerprint = [0; 4];
+    fingerprint.copy_from_slice(&hash[..4]);
+    fingerprint
+}
+
+// The fields of a serialized extended key
+struct Encoded {
+    version: [u8; 4],
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: u32,
+    chain_code: [u8; 32],
+    key: Vec<u8>,
+}
+
+impl Encoded {
+    fn encode(&self) -> String {
+        let mut data = Vec::with_capacity(ENCODED_SIZE);
+        data.extend_from_slice(&self.version);
+        data.push(self.depth);
+        data.extend_from_slice(&self.parent_fingerprint);
+        data.extend_from_slice(&self.child_number.to_be_bytes());
+        data.extend_from_slice(&self.chain_code);
+        data.extend_from_slice(&self.key);
+
+        base58::encode_check(&data)
+    }
+
+    fn decode(s: &str) -> Result<Encoded> {
+        let data = base58::decode_check(s).ok_or(Error::InvalidExtendedKey)?;
+        if data.len() != ENCODED_SIZE {
+            return Err(Error::InvalidExtendedKey);
+        }
+
+        let mut version = [0; 4];
+        version.copy_from_slice(&data[0..4]);
+        let mut parent_fingerprint = [0; 4];
+        parent_fingerprint.copy_from_slice(&data[5..9]);
+        let mut child_number = [0; 4];
+        child_number.copy_from_slice(&data[9..13]);
+        let mut chain_code = [0; 32];
+        chain_code.copy_from_slice(&data[13..45]);
+
+        Ok(Encoded {
+            version,
+            depth: data[4],
+            parent_fingerprint,
+            child_number: u32::from_be_bytes(child_number),
+            chain_code,
+            key: data[45..].to_vec(),
+        })
+    }
+}
+
+/// Parses a derivation path such as `m/44'/0'/0'/0/1`. Hardened indexes are
+/// marked with `'` or `h`; the leading `m` is optional.
+pub fn parse_path(path: &str) -> Result<Vec<u32>> {
+    path.split('/')
+        .filter(|part| !part.is_empty() && *part != "m")
+        .map(|part| {
+            let (number, hardened) = match part.strip_suffix('\'').or_else(|| part.strip_suffix('h')) {
+                Some(number) => (number, true),
+                None => (part, false),
+            };
+            let index = number.parse::<u32>().map_err(|_| Error::InvalidDerivationPath)?;
+            if index >= HARDENED {
+                return Err(Error::InvalidDerivationPath);
+            }
+            Ok(if hardened { index + HARDENED } else { index })
+        })
+        .collect()
+}
+
+pub fn format_path(path: &[u32]) -> String {
+    let mut formatted = "m".to_owned();
+    for &index in path {
+        if index >= HARDENED {
+            formatted.push_str(&format!("/{}'", index - HARDENED));
+        } else {
+            formatted.push_str(&format!("/{}", index));
+        }
+    }
+    formatted
+}
+
+impl ExtendedPrivKey {
+    pub fn new_master(seed: &[u8]) -> Result<ExtendedPrivKey> {
+        let secp = Secp256k1::new();
+        let (key, chain_code) = hmac_sha512(MASTER_KEY, seed);
+        let secret_key = SecretKey::from_slice(&secp, &key).map_err(|_| Error::InvalidExtendedKey)?;
+
+        Ok(ExtendedPrivKey {
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+            chain_code,
+            secret_key,
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let secp = Secp256k1::new();
+        PublicKey::from_secret_key(&secp, &self.secret_key).unwrap()
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<ExtendedPrivKey> {
+        let depth = self.depth.checked_add(1).ok_or(Error::InvalidDerivationPath)?;
+        let secp = Secp256k1::new();
+        let public_key = self.public_key();
+
+        let mut data = Vec::with_capacity(37);
+        if index >= HARDENED {
+            data.push(0);
+            data.extend_from_slice(&self.secret_key[..]);
+        } else {
+            data.extend_from_slice(&serialize_public_key(&public_key));
+        }
+        data.extend_from_slice(&index.to_be_bytes());
+
+        let (tweak, chain_code) = hmac_sha512(&self.chain_code, &data);
+        let mut secret_key = SecretKey::from_slice(&secp, &tweak).map_err(|_| Error::InvalidExtendedKey)?;
+        secret_key.add_assign(&secp, &self.secret_key).map_err(|_| Error::InvalidExtendedKey)?;
+
+        Ok(ExtendedPrivKey {
+            depth,
+            parent_fingerprint: fingerprint(&public_key),
+            child_number: index,
+            chain_code,
+            secret_key,
+        })
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Result<ExtendedPrivKey> {
+        let mut key = self.clone();
+        for &index in path {
+            key = key.derive_child(index)?;
+        }
+        Ok(key)
+    }
+
+    pub fn to_extended_pub(&self) -> ExtendedPubKey {
+        ExtendedPubKey {
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            public_key: self.public_key(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut key = vec![0];
+        key.extend_from_slice(&self.secret_key[..]);
+
+        Encoded {
+            version: XPRV_VERSION,
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            key,
+        }.encode()
+    }
+
+    pub fn decode(s: &str) -> Result<ExtendedPrivKey> {
+        let encoded = Encoded::decode(s)?;
+        if encoded.version != XPRV_VERSION || encoded.key[0] != 0 {
+            return Err(Error::InvalidExtendedKey);
+        }
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &encoded.key[1..])
+            .map_err(|_| Error::InvalidExtendedKey)?;
+
+        Ok(ExtendedPrivKey {
+            depth: encoded.depth,
+            parent_fingerprint: encoded.parent_fingerprint,
+            child_number: encoded.child_number,
+            chain_code: encoded.chain_code,
+            secret_key,
+        })
+    }
+}
+
+impl ExtendedPubKey {
+    pub fn derive_child(&self, index: u32) -> Result<ExtendedPubKey> {
+        if index >= HARDENED {
+            return Err(Error::HardenedDerivation);
+        }
+        let depth = self.depth.checked_add(1).ok_or(Error::InvalidDerivationPath)?;
+        let secp = Secp256k1::new();
+
+        let mut data = Vec::with_capacity(37);
+        data.extend_from_slice(&serialize_public_key(&self.public_key));
+        data.extend_from_slice(&index.to_be_bytes());
+
+        let (tweak, chain_code) = hmac_sha512(&self.chain_code, &data);
+        let tweak = SecretKey::from_slice(&secp, &tweak).map_err(|_| Error::InvalidExtendedKey)?;
+        let mut public_key = self.public_key;
+        public_key.add_exp_assign(&secp, &tweak).map_err(|_| Error::InvalidExtendedKey)?;
+
+        Ok(ExtendedPubKey {
+            depth,
+            parent_fingerprint: fingerprint(&self.public_key),
+            child_number: index,
+            chain_code,
+            public_key,
+        })
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Result<ExtendedPubKey> {
+        let mut key = self.clone();
+        for &index in path {
+            key = key.derive_child(index)?;
+        }
+        Ok(key)
+    }
+
+    pub fn encode(&self) -> String {
+        Encoded {
+            version: XPUB_VERSION,
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            key: serialize_public_key(&self.public_key),
+        }.encode()
+    }
+
+    pub fn decode(s: &str) -> Result<ExtendedPubKey> {
+        let encoded = Encoded::decode(s)?;
+        if encoded.version != XPUB_VERSION {
+            return Err(Error::InvalidExtendedKey);
+        }
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::from_slice(&secp, &encoded.key)
+            .map_err(|_| Error::InvalidExtendedKey)?;
+
+        Ok(ExtendedPubKey {
+            depth: encoded.depth,
+            parent_fingerprint: encoded.parent_fingerprint,
+            child_number: encoded.child_number,
+            chain_code: encoded.chain_code,
+            public_key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::FromHex;
+
+    // BIP32 test vector 1: each path from the master key, with its extended
+    // private and public keys
+    const SEED: &str = "000102030405060708090a0b0c0d0e0f";
+    const VECTOR: &[(&str, &str, &str)] = &[
+        (
+            "m",
+            "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi",
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+        ),
+        (
+            "m/0'",
+            "xprv9uHRZZhk6KAJC1avXpDAp4MDc3sQKNxDiPvvkX8Br5ngLNv1TxvUxt4cV1rGL5hj6KCesnDYUhd7oWgT11eZG7XnxHrnYeSvkzY7d2bhkJ7",
+            "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw",
+        ),
+        (
+            "m/0'/1",
+            "xprv9wTYmMFdV23N2TdNG573QoEsfRrWKQgWeibmLntzniatZvR9BmLnvSxqu53Kw1UmYPxLgboyZQaXwTCg8MSY3H2EU4pWcQDnRnrVA1xe8fs",
+            "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ",
+        ),
+        (
+            "m/0'/1/2'",
+            "xprv9z4pot5VBttmtdRTWfWQmoH1taj2axGVzFqSb8C9xaxKymcFzXBDptWmT7FwuEzG3ryjH4ktypQSAewRiNMjANTtpgP4mLTj34bhnZX7UiM",
+            "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5",
+        ),
+        (
+            "m/0'/1/2'/2",
+            "xprvA2JDeKCSNNZky6uBCviVfJSKyQ1mDYahRjijr5idH2WwLsEd4Hsb2Tyh8RfQMuPh7f7RtyzTtdrbdqqsunu5Mm3wDvUAKRHSC34sJ7in334",
+            "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV",
+        ),
+        (
+            "m/0'/1/2'/2/1000000000",
+            "xprvA41z7zogVVwxVSgdKUHDy1SKmdb533PjDz7J6N6mV6uS3ze1ai8FHa8kmHScGpWmj4WggLyQjgPie1rFSruoUihUZREPSL39UNdE3BBDu76",
+            "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy",
+        ),
+    ];
+
+    fn master() -> ExtendedPrivKey {
+        ExtendedPrivKey::new_master(&SEED.from_hex().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn derives_the_private_keys_of_vector_1() {
+        for &(path, xprv, xpub) in VECTOR {
+            let key = master().derive_path(&parse_path(path).unwrap()).unwrap();
+            assert_eq!(key.encode(), xprv, "{}", path);
+            assert_eq!(key.to_extended_pub().encode(), xpub, "{}", path);
+        }
+    }
+
+    #[test]
+    fn derives_the_non_hardened_public_keys_of_vector_1() {
+        for pair in VECTOR.windows(2) {
+            let (_, _, parent) = pair[0];
+            let (path, _, child) = pair[1];
+            let index = *parse_path(path).unwrap().last().unwrap();
+            if index >= HARDENED {
+                continue;
+            }
+            let key = ExtendedPubKey::decode(parent).unwrap().derive_child(index).unwrap();
+            assert_eq!(key.encode(), child, "{}", path);
+        }
+    }
+
+    #[test]
+    fn decodes_the_keys_of_vector_1() {
+        for &(_, xprv, xpub) in VECTOR {
+            assert_eq!(ExtendedPrivKey::decode(xprv).unwrap().encode(), xprv);
+            assert_eq!(ExtendedPubKey::decode(xpub).unwrap().encode(), xpub);
+        }
+    }
+
+    #[test]
+    fn rejects_paths_deeper_than_255() {
+        let mut key = master();
+        key.depth = u8::MAX;
+        assert!(matches!(key.derive_child(0), Err(Error::InvalidDerivationPath)));
+        assert!(matches!(key.to_extended_pub().derive_child(0), Err(Error::InvalidDerivationPath)));
+    }
+}
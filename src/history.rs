@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+use std::fmt;
+use address::Address;
+use blockchain::Blockchain;
+use mempool::MemPool;
+use transaction::{TX, TXOutput, TXSource};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Coins paid to the wallet by others.
+    Received,
+    /// Coins paid by the wallet to others.
+    Sent,
+    /// Coins the wallet paid back to itself.
+    ToSelf,
+    /// A block reward paid to the wallet.
+    Mined,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Direction::Received => "received",
+            Direction::Sent => "sent",
+            Direction::ToSelf => "sent to self",
+            Direction::Mined => "mined",
+        })
+    }
+}
+
+/// A transaction that paid or spent coins of a wallet.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub txid: String,
+    pub direction: Direction,
+    /// The amount received, or sent to others.
+    pub amount: u64,
+    /// The fee, when paid by the wallet.
+    pub fee: Option<u64>,
+    /// Who sent the coins, or who they were sent to.
+    pub counterparties: Vec<Address>,
+    /// The height of the block holding the transaction, `None` while it is
+    /// in the mempool.
+    pub height: Option<usize>,
+    pub confirmations: usize,
+    /// The time of the block, or when the transaction entered the mempool,
+    /// in seconds since the epoch.
+    pub timestamp: u64,
+    /// The memo attached to the transaction, if any.
+    pub memo: String,
+}
+
+// Adds `address` unless it is already listed
+fn push_unique(addresses: &mut Vec<Address>, address: Address) {
+    if !addresses.contains(&address) {
+        addresses.push(address);
+    }
+}
+
+fn entry<S: TXSource + ?Sized>(
+    tx: &TX,
+    source: &S,
+    own: &HashSet<&Address>,
+) -> Option<HistoryEntry> {
+    let is_own = |output: &TXOutput| output.payee().is_some_and(|address| own.contains(&address));
+
+    let received: u64 = tx.outputs()
+        .iter()
+        .filter(|output| is_own(output))
+        .map(|output| output.value)
+        .sum();
+
+    let memo = match *tx {
+        TX::Standard(ref stx) => stx.memo.clone(),
+        TX::Coinbase(_) => String::new(),
+    };
+
+    let (direction, amount, fee, counterparties) = match *tx {
+        TX::Coinbase(_) => {
+            if received == 0 {
+                return None;
+            }
+            (Direction::Mined, received, None, Vec::new())
+        }
+        TX::Standard(ref stx) => {
+            let prev_outputs: Vec<_> = stx.inputs
+                .iter()
+                .filter_map(|input| {
+                    source
+                        .find_transaction(&input.txid)
+                        .and_then(|prev_tx| prev_tx.outputs().get(input.vout))
+                })
+                .collect();
+            let spends = prev_outputs.iter().any(|output| is_own(output));
+
+            let mut counterparties = Vec::new();
+            if spends {
+                let others: Vec<_> = stx.outputs
+                    .iter()
+                    .filter(|output| !is_own(output))
+                    .collect();
+                let sent: u64 = others.iter().map(|output| output.value).sum();
+                for output in others {
+                    if let Some(address) = output.payee() {
+                        push_unique(&mut counterparties, address);
+                    }
+                }
+                let direction = if sent == 0 { Direction::ToSelf } else { Direction::Sent };
+                (direction, sent, stx.fee(source).ok(), counterparties)
+            } else if received > 0 {
+                for output in prev_outputs {
+                    if let Some(address) = output.payee() {
+                        push_unique(&mut counterparties, address);
+                    }
+                }
+                (Direction::Received, received, None, counterparties)
+            } else {
+                return None;
+            }
+        }
+    };
+
+    Some(HistoryEntry {
+        txid: tx.id(),
+        direction,
+        amount,
+        fee,
+        counterparties,
+        height: None,
+        confirmations: 0,
+        timestamp: 0,
+        memo,
+    })
+}
+
+/// Every transaction in the blockchain and mempool that pays or spends
+/// `addresses`, oldest first, with pending transactions last.
+pub fn history(bc: &Blockchain, mp: &MemPool, addresses: &[Address]) -> Vec<HistoryEntry> {
+    let own: HashSet<&Address> = addresses.iter().collect();
+    let view = mp.view(bc);
+
+    let mut blocks: Vec<_> = bc.iter().map(|(_, block)| block).collect();
+    blocks.reverse();
+    let tip = blocks.len().saturating_sub(1);
+
+    let mut entries = Vec::new();
+    for (height, block) in blocks.iter().enumerate() {
+        for tx in block.transactions.iter() {
+            if let Some(mut entry) = entry(tx, &view, &own) {
+                entry.height = Some(height);
+                entry.confirmations = tip - height + 1;
+                entry.timestamp = block.timestamp as u64;
+                entries.push(entry);
+            }
+        }
+    }
+
+    for tx in mp.transactions() {
+        if let Some(mut entry) = entry(tx, &view, &own) {
+            entry.timestamp = mp.entry_time(&entry.txid).unwrap_or(0);
+            entries.push(entry);
+        }
+    }
+
+    entries
+}
@@ -0,0 +1,178 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rustc_serialize::hex::{FromHex, ToHex};
+use address::Address;
+use constants::PREIMAGE_SIZE;
+use encryption::random_bytes;
+use error::{Error, Result};
+use script::{self, Op, Script};
+use wallet::HashType;
+
+/// A hash time-locked contract: an output the recipient can spend by
+/// revealing the preimage of `payment_hash`, or that the sender can take
+/// back once `timeout`, a height or time, has passed. Two contracts with
+/// the same hash make an atomic swap, since claiming one reveals the
+/// preimage that claims the other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Htlc {
+    pub payment_hash: Vec<u8>,
+    pub recipient: Address,
+    pub sender: Address,
+    pub timeout: u64,
+}
+
+/// The SHA-256 hash of `preimage`.
+pub fn payment_hash(preimage: &[u8]) -> Vec<u8> {
+    let mut hash = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(preimage);
+    hasher.result(&mut hash);
+    hash.to_vec()
+}
+
+/// A random preimage for a new contract.
+pub fn new_preimage() -> Vec<u8> {
+    random_bytes(PREIMAGE_SIZE)
+}
+
+// The operation hashing a public key the way `hash_type` addresses do
+fn hash_op(hash_type: HashType) -> Op {
+    match hash_type {
+        HashType::Sha256 => Op::Sha256,
+        HashType::Hash160 => Op::Hash160,
+    }
+}
+
+// The address of a public key hash checked with `op`
+fn pubkey_hash_address(op: &Op, pubkey_hash: &[u8]) -> Option<Address> {
+    let hash_type = match *op {
+        Op::Sha256 => HashType::Sha256,
+        Op::Hash160 => HashType::Hash160,
+        _ => return None,
+    };
+    Address::from_pubkey_hash(&pubkey_hash.to_hex(), hash_type).ok()
+}
+
+impl Htlc {
+    /// A contract paying `recipient` against the preimage of
+    /// `payment_hash`, refunded to `sender` from `timeout`, which a script
+    /// number must hold. Both must be public key hash addresses.
+    pub fn new(payment_hash: Vec<u8>, recipient: Address, sender: Address, timeout: u64) -> Result<Htlc> {
+        if recipient.is_script() || sender.is_script() {
+            return Err(Error::InvalidAddress);
+        }
+        if payment_hash.len() != 32 {
+            return Err(Error::InvalidHTLC);
+        }
+        if timeout > i64::MAX as u64 {
+            return Err(Error::LockTimeOutOfRange);
+        }
+
+        Ok(Htlc {
+            payment_hash,
+            recipient,
+            sender,
+            timeout,
+        })
+    }
+
+    /// The script locking the contract's output.
+    pub fn script(&self) -> Script {
+        Script::new(vec![
+            Op::If,
+            Op::Sha256,
+            Op::Push(self.payment_hash.clone()),
+            Op::EqualVerify,
+            Op::Dup,
+            hash_op(self.recipient.hash_type()),
+            Op::Push(self.recipient.pubkey_hash().from_hex().unwrap()),
+            Op::Else,
+            Op::Push(script::encode_num(self.timeout as i64)),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::Dup,
+            hash_op(self.sender.hash_type()),
+            Op::Push(self.sender.pubkey_hash().from_hex().unwrap()),
+            Op::EndIf,
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// The contract locked by `script`, or `None` if it is not one.
+    pub fn parse(script: &Script) -> Option<Htlc> {
+        match *script.ops.as_slice() {
+            [Op::If, Op::Sha256, Op::Push(ref payment_hash), Op::EqualVerify, Op::Dup,
+             ref recipient_op, Op::Push(ref recipient_hash), Op::Else, Op::Push(ref timeout),
+             Op::CheckLockTimeVerify, Op::Drop, Op::Dup, ref sender_op, Op::Push(ref sender_hash),
+             Op::EndIf, Op::EqualVerify, Op::CheckSig] => {
+                let timeout = script::decode_num(timeout).ok().filter(|&timeout| timeout >= 0)?;
+                Htlc::new(
+                    payment_hash.clone(),
+                    pubkey_hash_address(recipient_op, recipient_hash)?,
+                    pubkey_hash_address(sender_op, sender_hash)?,
+                    timeout as u64,
+                ).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// The unlocking script with which the recipient claims the output.
+    pub fn claim_script(&self, signature: Vec<u8>, public_key: Vec<u8>, preimage: &[u8]) -> Result<Script> {
+        if payment_hash(preimage) != self.payment_hash {
+            return Err(Error::InvalidPreimage);
+        }
+        Ok(Script::new(vec![
+            Op::Push(signature),
+            Op::Push(public_key),
+            Op::Push(preimage.to_vec()),
+            Op::Push(script::encode_num(1)),
+        ]))
+    }
+
+    /// The unlocking script with which the sender takes the output back
+    /// after the timeout.
+    pub fn refund_script(signature: Vec<u8>, public_key: Vec<u8>) -> Script {
+        Script::new(vec![
+            Op::Push(signature),
+            Op::Push(public_key),
+            Op::Push(script::encode_num(0)),
+        ])
+    }
+
+    /// The preimage revealed by `unlock` if it claims the contract.
+    pub fn preimage(&self, unlock: &Script) -> Option<Vec<u8>> {
+        match *unlock.ops.as_slice() {
+            [Op::Push(_), Op::Push(_), Op::Push(ref preimage), Op::Push(_)]
+                if payment_hash(preimage) == self.payment_hash => Some(preimage.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(byte: &str) -> Address {
+        Address::from_pubkey_hash(&byte.repeat(20), HashType::Hash160).unwrap()
+    }
+
+    #[test]
+    fn contracts_round_trip_through_their_script() {
+        let htlc = Htlc::new(payment_hash(b"secret"), address("11"), address("22"), 1_000).unwrap();
+        assert_eq!(Htlc::parse(&htlc.script()), Some(htlc));
+    }
+
+    #[test]
+    fn timeouts_must_fit_a_script_number() {
+        let max = i64::MAX as u64;
+        let htlc = Htlc::new(payment_hash(b"secret"), address("11"), address("22"), max).unwrap();
+        assert_eq!(Htlc::parse(&htlc.script()).unwrap().timeout, max);
+        match Htlc::new(payment_hash(b"secret"), address("11"), address("22"), max + 1) {
+            Err(Error::LockTimeOutOfRange) => {}
+            other => panic!("expected LockTimeOutOfRange, got {:?}", other),
+        }
+    }
+}
@@ -7,6 +7,7 @@ extern crate num;
 extern crate clap;
 extern crate secp256k1;
 extern crate rand;
+extern crate rustc_serialize;
 
 pub mod block;
 pub mod serialize;
@@ -18,3 +19,18 @@ pub mod wallet;
 pub mod mempool;
 pub mod error;
 pub mod constants;
+pub mod coinselect;
+pub mod fees;
+pub mod encryption;
+pub mod base58;
+pub mod address;
+pub mod hd;
+pub mod history;
+pub mod balance;
+pub mod message;
+pub mod mnemonic;
+pub mod script;
+pub mod partial;
+pub mod htlc;
+pub mod schnorr;
+pub mod verify;
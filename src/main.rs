@@ -4,7 +4,7 @@ use std::process;
 
 fn main() {
     cli::run().unwrap_or_else(|err| {
-        println!("{}", err.to_string());
+        println!("{}", err);
         process::exit(1);
     });
 }
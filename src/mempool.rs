@@ -2,15 +2,76 @@ use transaction::*;
 use std::fs::File;
 use std::io::prelude::*;
 use serialize::{serialize, deserialize};
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+use block::Block;
 use blockchain::Blockchain;
-use error::Result;
-use constants::MEMPOOL;
+use error::{Error, Result};
+use constants::{MEMPOOL, MAX_ANCESTORS, MAX_DESCENDANTS, MEMPOOL_MAX_SIZE, MEMPOOL_EXPIRY};
+use utxo::{self, UTXO};
+use wallet::hash_public_key;
+use address::Address;
 
 #[derive(Serialize, Deserialize)]
 pub struct MemPool {
     txs: VecDeque<TX>,
     tx_ids: HashSet<String>,
+    // When each transaction entered the mempool, in seconds since the epoch
+    #[serde(default)]
+    entry_times: HashMap<String, u64>,
+    /// The largest total size of the transactions, in bytes. When exceeded,
+    /// the transactions with the lowest fee rate are evicted.
+    #[serde(default = "default_max_size")]
+    pub max_size: usize,
+    /// How long a transaction may stay in the mempool, in seconds.
+    #[serde(default = "default_expiry")]
+    pub expiry: u64,
+    // Each output spent by a transaction in the mempool, and the spending txid
+    #[serde(skip)]
+    spent: HashMap<(String, usize), String>,
+    // Transactions replaced, trimmed or expired without confirming, which
+    // the fee estimator should stop tracking
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dropped: Vec<String>,
+}
+
+// The transactions of a mempool, kept so that a push can be undone
+struct Snapshot {
+    txs: VecDeque<TX>,
+    tx_ids: HashSet<String>,
+    entry_times: HashMap<String, u64>,
+    spent: HashMap<(String, usize), String>,
+    dropped: Vec<String>,
+}
+
+/// The blockchain with the unconfirmed transactions of a mempool layered on top.
+pub struct MemPoolView<'a> {
+    mempool: &'a MemPool,
+    blockchain: &'a Blockchain,
+}
+
+fn default_max_size() -> usize {
+    MEMPOOL_MAX_SIZE
+}
+
+fn default_expiry() -> u64 {
+    MEMPOOL_EXPIRY
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl<'a> TXSource for MemPoolView<'a> {
+    fn find_transaction(&self, txid: &str) -> Option<&TX> {
+        self.mempool
+            .get(txid)
+            .or_else(|| self.blockchain.find_transaction(txid))
+    }
 }
 
 
@@ -28,48 +89,869 @@ impl MemPool {
                 let mut contents = String::new();
                 f.read_to_string(&mut contents).unwrap();
 
-                deserialize(&contents)
+                let mut mempool: MemPool = deserialize(&contents);
+                let txs: Vec<TX> = mempool.txs.iter().cloned().collect();
+                for tx in txs.iter() {
+                    mempool.index_spent(tx);
+                    mempool.entry_times.entry(tx.id()).or_insert_with(now);
+                }
+                mempool.expire();
+
+                mempool
+            }
+            Err(_) => MemPool::new(),
+        }
+    }
+
+    /// An empty mempool with the default size cap and expiry.
+    pub fn new() -> MemPool {
+        MemPool {
+            txs: VecDeque::new(),
+            tx_ids: HashSet::new(),
+            entry_times: HashMap::new(),
+            max_size: MEMPOOL_MAX_SIZE,
+            expiry: MEMPOOL_EXPIRY,
+            spent: HashMap::new(),
+            dropped: Vec::new(),
+        }
+    }
+
+    fn index_spent(&mut self, tx: &TX) {
+        if let TX::Standard(ref stx) = *tx {
+            let txid = tx.id();
+            for input in stx.inputs.iter() {
+                self.spent.insert((input.txid.clone(), input.vout), txid.clone());
+            }
+        }
+    }
+
+    fn unindex_spent(&mut self, tx: &TX) {
+        if let TX::Standard(ref stx) = *tx {
+            for input in stx.inputs.iter() {
+                self.spent.remove(&(input.txid.clone(), input.vout));
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            txs: self.txs.clone(),
+            tx_ids: self.tx_ids.clone(),
+            entry_times: self.entry_times.clone(),
+            spent: self.spent.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.txs = snapshot.txs;
+        self.tx_ids = snapshot.tx_ids;
+        self.entry_times = snapshot.entry_times;
+        self.spent = snapshot.spent;
+        self.dropped = snapshot.dropped;
+    }
+
+    fn insert(&mut self, txid: String, tx: TX) {
+        self.index_spent(&tx);
+        self.entry_times.insert(txid.clone(), now());
+        self.tx_ids.insert(txid);
+        self.txs.push_back(tx);
+    }
+
+    pub fn remove(&mut self, txid: &str) -> Option<TX> {
+        if !self.tx_ids.remove(txid) {
+            return None;
+        }
+        self.entry_times.remove(txid);
+        let position = self.txs.iter().position(|tx| tx.id() == txid)?;
+        let tx = self.txs.remove(position)?;
+        self.unindex_spent(&tx);
+
+        Some(tx)
+    }
+
+    /// Removes the transactions that have been in the mempool for longer
+    /// than `expiry` seconds, along with their descendants.
+    pub fn expire(&mut self) {
+        let cutoff = now().saturating_sub(self.expiry);
+        let expired: Vec<String> = self.entry_times
+            .iter()
+            .filter(|&(_, &time)| time < cutoff)
+            .map(|(txid, _)| txid.clone())
+            .collect();
+
+        for txid in expired {
+            self.discard(&txid);
+        }
+    }
+
+    /// The total size of the transactions in the mempool, in bytes.
+    pub fn size(&self) -> usize {
+        self.txs
+            .iter()
+            .map(|tx| match *tx {
+                TX::Standard(ref stx) => stx.size(),
+                TX::Coinbase(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Evicts the transactions with the lowest fee rate, and their
+    /// descendants, until the mempool is no larger than `max_size`.
+    pub fn trim(&mut self, bc: &Blockchain) {
+        while self.size() > self.max_size {
+            let lowest = {
+                let view = self.view(bc);
+                self.txs
+                    .iter()
+                    .filter_map(|tx| match *tx {
+                        TX::Standard(ref stx) => {
+                            Some((tx.id(), stx.fee_rate(&view).unwrap_or(0.0)))
+                        }
+                        TX::Coinbase(_) => None,
+                    })
+                    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                    .map(|(txid, _)| txid)
+            };
+
+            match lowest {
+                Some(txid) => self.discard(&txid),
+                None => break,
             }
-            Err(_) => MemPool {
-                txs: VecDeque::new(),
-                tx_ids: HashSet::new(),
-            },
         }
     }
 
+    /// Updates the mempool after `block` is added to the blockchain: its
+    /// transactions are removed, as are transactions spending the same
+    /// outputs and their descendants.
+    pub fn block_connected(&mut self, block: &Block) {
+        for tx in block.transactions.iter() {
+            // The descendants of a confirmed transaction remain valid
+            if self.remove(&tx.id()).is_some() {
+                continue;
+            }
+            if let TX::Standard(ref stx) = *tx {
+                for input in stx.inputs.iter() {
+                    if let Some(spender) = self.spender(&input.txid, input.vout).cloned() {
+                        self.remove_with_descendants(&spender);
+                    }
+                }
+            }
+        }
+
+        self.expire();
+    }
+
+    /// Updates the mempool after `block` is removed from the blockchain,
+    /// returning its transactions to the mempool where they are still valid.
+    pub fn block_disconnected(&mut self, bc: &Blockchain, block: &Block) {
+        for tx in block.transactions.iter() {
+            let _ = self.push(bc, tx.clone());
+        }
+
+        // With a lower height, some spent block rewards are no longer mature
+        // and some lock times no longer passed
+        let height = bc.height() + 1;
+        let immature: Vec<String> = self.txs
+            .iter()
+            .filter(|tx| match **tx {
+                TX::Standard(ref stx) => {
+                    stx.check_maturity(bc, height).is_err()
+                        || stx.check_lock_times(bc, height, bc.median_time_past()).is_err()
+                }
+                TX::Coinbase(_) => false,
+            })
+            .map(|tx| tx.id())
+            .collect();
+        for txid in immature {
+            self.discard(&txid);
+        }
+    }
+
+    /// Removes a transaction and every transaction spending its outputs.
+    pub fn remove_with_descendants(&mut self, txid: &str) {
+        for descendant in self.descendants(txid) {
+            self.remove(&descendant);
+        }
+        self.remove(txid);
+    }
+
+    // Removes a transaction and its descendants as they leave the mempool
+    // without confirming, recording them as dropped
+    fn discard(&mut self, txid: &str) {
+        let mut txids = self.descendants(txid);
+        txids.insert(txid.to_owned());
+        for txid in txids {
+            if self.remove(&txid).is_some() {
+                self.dropped.push(txid);
+            }
+        }
+    }
+
+    /// Takes the transactions that have left the mempool without confirming
+    /// since the last call, other than by conflicting with a block.
+    pub fn take_dropped(&mut self) -> Vec<String> {
+        mem::take(&mut self.dropped)
+    }
+
+    /// The mempool transaction spending the given output, if there is one.
+    pub fn spender(&self, txid: &str, vout: usize) -> Option<&String> {
+        self.spent.get(&(txid.to_owned(), vout))
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&TX> {
+        if !self.tx_ids.contains(txid) {
+            return None;
+        }
+        self.txs.iter().find(|tx| tx.id() == txid)
+    }
+
+    /// The transactions in the order they entered the mempool.
+    pub fn transactions(&self) -> impl Iterator<Item = &TX> {
+        self.txs.iter()
+    }
+
+    /// When the transaction entered the mempool, in seconds since the epoch.
+    pub fn entry_time(&self, txid: &str) -> Option<u64> {
+        self.entry_times.get(txid).cloned()
+    }
+
+    pub fn view<'a>(&'a self, bc: &'a Blockchain) -> MemPoolView<'a> {
+        MemPoolView {
+            mempool: self,
+            blockchain: bc,
+        }
+    }
+
+    fn parents(&self, tx: &StandardTX) -> HashSet<String> {
+        tx.inputs
+            .iter()
+            .filter(|input| self.tx_ids.contains(&input.txid))
+            .map(|input| input.txid.clone())
+            .collect()
+    }
+
+    fn ancestors(&self, tx: &StandardTX) -> HashSet<String> {
+        let mut ancestors = HashSet::new();
+        let mut queue: Vec<String> = self.parents(tx).into_iter().collect();
+
+        while let Some(txid) = queue.pop() {
+            if !ancestors.insert(txid.clone()) {
+                continue;
+            }
+            if let Some(TX::Standard(parent)) = self.get(&txid) {
+                queue.extend(self.parents(parent));
+            }
+        }
+
+        ancestors
+    }
+
+    fn descendants(&self, txid: &str) -> HashSet<String> {
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for tx in self.txs.iter() {
+            if let TX::Standard(ref stx) = *tx {
+                for parent in self.parents(stx) {
+                    children.entry(parent).or_default().push(tx.id());
+                }
+            }
+        }
+
+        let mut descendants = HashSet::new();
+        let mut queue = vec![txid.to_owned()];
+        while let Some(txid) = queue.pop() {
+            if let Some(txids) = children.get(&txid) {
+                for child in txids {
+                    if descendants.insert(child.clone()) {
+                        queue.push(child.clone());
+                    }
+                }
+            }
+        }
+
+        descendants
+    }
+
+    fn check_limits(&self, tx: &StandardTX) -> Result<()> {
+        let ancestors = self.ancestors(tx);
+        if ancestors.len() + 1 > MAX_ANCESTORS {
+            return Err(Error::TooManyAncestors);
+        }
+        for txid in ancestors.iter() {
+            if self.descendants(txid).len() + 1 > MAX_DESCENDANTS {
+                return Err(Error::TooManyDescendants);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The mempool transactions spending the same outputs as `tx`
+    fn conflicts(&self, tx: &StandardTX) -> Result<HashSet<String>> {
+        let mut outpoints = HashSet::new();
+        let mut conflicts = HashSet::new();
+
+        for input in tx.inputs.iter() {
+            if let Some(txid) = self.spender(&input.txid, input.vout) {
+                conflicts.insert(txid.clone());
+            }
+            // A transaction may not spend the same output twice
+            if !outpoints.insert((input.txid.clone(), input.vout)) {
+                return Err(Error::ConflictingTransaction);
+            }
+        }
+
+        Ok(conflicts)
+    }
+
+    // Checks that `tx` may replace the `conflicts`, returning every
+    // transaction the replacement evicts
+    fn replace(
+        &self,
+        bc: &Blockchain,
+        tx: &StandardTX,
+        conflicts: &HashSet<String>,
+    ) -> Result<HashSet<String>> {
+        use self::Error::*;
+        let view = self.view(bc);
+        let fee = tx.fee(&view)?;
+        let fee_rate = tx.fee_rate(&view)?;
+
+        let mut evicted = HashSet::new();
+        for txid in conflicts.iter() {
+            match self.get(txid) {
+                Some(TX::Standard(original)) if original.replaceable => {
+                    if fee_rate <= original.fee_rate(&view)? {
+                        return Err(InsufficientReplacementFee);
+                    }
+                }
+                _ => return Err(ConflictingTransaction),
+            }
+            evicted.insert(txid.clone());
+            evicted.extend(self.descendants(txid));
+        }
+
+        // The replacement may not depend on a transaction it evicts
+        if tx.inputs.iter().any(|input| evicted.contains(&input.txid)) {
+            return Err(ConflictingTransaction);
+        }
+
+        // It must also pay more than everything it evicts
+        let mut evicted_fee = 0;
+        for txid in evicted.iter() {
+            if let Some(TX::Standard(old)) = self.get(txid) {
+                evicted_fee = old.fee(&view)?
+                    .checked_add(evicted_fee)
+                    .ok_or(InvalidTransaction)?;
+            }
+        }
+        if fee <= evicted_fee {
+            return Err(InsufficientReplacementFee);
+        }
+
+        Ok(evicted)
+    }
+
+    // Checks that the confirmed outputs spent by `tx` have not already been
+    // spent in the blockchain
+    fn check_unspent(&self, bc: &Blockchain, tx: &StandardTX) -> Result<()> {
+        let utxo = utxo::find(bc);
+        for input in tx.inputs.iter() {
+            if self.tx_ids.contains(&input.txid) {
+                continue;
+            }
+            let unspent = utxo
+                .get(&input.txid)
+                .is_some_and(|entries| entries.iter().any(|entry| entry.vout == input.vout));
+            if !unspent {
+                return Err(Error::OutputAlreadySpent);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_spent(&self, utxo: &mut UTXO) {
+        for tx in self.txs.iter() {
+            if let TX::Standard(ref stx) = *tx {
+                for input in stx.inputs.iter() {
+                    if let Some(entries) = utxo.get_mut(&input.txid) {
+                        entries.retain(|entry| entry.vout != input.vout);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The confirmed UTXO set, without the outputs already spent by
+    /// transactions in the mempool.
+    pub fn utxo(&self, bc: &Blockchain) -> UTXO {
+        let mut utxo = utxo::find(bc);
+        self.remove_spent(&mut utxo);
+        utxo
+    }
+
+    /// Like `utxo`, but also including the unconfirmed change of the owner of
+    /// `address`: outputs paying back to it from transactions it signed.
+    pub fn utxo_with_change(&self, bc: &Blockchain, address: &Address) -> UTXO {
+        let mut utxo = utxo::find(bc);
+        let pubkey_hash = address.pubkey_hash();
+
+        for tx in self.txs.iter() {
+            if let TX::Standard(ref stx) = *tx {
+                let own = stx.inputs
+                    .iter()
+                    .all(|input| match input.pubkey {
+                        Some(ref pubkey) => hash_public_key(pubkey, address.hash_type()) == pubkey_hash,
+                        None => false,
+                    });
+                if own {
+                    let mut entries = utxo::new_entries(tx);
+                    entries.retain(|entry| entry.output.pubkey_hash == pubkey_hash);
+                    utxo.insert(tx.id(), entries);
+                }
+            }
+        }
+        self.remove_spent(&mut utxo);
+
+        utxo
+    }
+
     pub fn push(&mut self, bc: &Blockchain, tx: TX) -> Result<()> {
 
         let txid = tx.id();
+        if self.tx_ids.contains(&txid) {
+            return Ok(());
+        }
         match tx {
             TX::Coinbase(_) => (),
             TX::Standard(ref stx) => {
-                match stx.verify(bc){
-                    Ok(()) => {
-                        if self.tx_ids.insert(txid) {
-                            self.txs.push_back(tx.clone());
-                        }
-                    }
-                    Err(e) => return Err(e)
+                let conflicts = self.conflicts(stx)?;
+                let evicted = if conflicts.is_empty() {
+                    HashSet::new()
+                } else {
+                    self.replace(bc, stx, &conflicts)?
+                };
+                stx.verify(&self.view(bc))?;
+                stx.check_maturity(bc, bc.height() + 1)?;
+                stx.check_lock_times(bc, bc.height() + 1, bc.median_time_past())?;
+                self.check_unspent(bc, stx)?;
+                self.check_limits(stx)?;
+
+                // If the size cap evicts `tx` itself, the transactions it
+                // replaced and those trimmed to make room for it are restored
+                let snapshot = self.snapshot();
+                for txid in evicted.iter() {
+                    self.discard(txid);
+                }
+                self.insert(txid.clone(), tx.clone());
+
+                self.trim(bc);
+                if !self.tx_ids.contains(&txid) {
+                    self.restore(snapshot);
+                    return Err(Error::MemPoolFull);
                 }
             }
         }
         Ok(())
     }
 
-    pub fn pop(&mut self) -> Option<TX> {
-        let tx = self.txs.pop_front();
-        match tx {
-            Some(ref tx) => { self.tx_ids.remove(&tx.id()); },
-            None => (),
+    /// The transactions to mine in the next block, at most `max_size` bytes
+    /// of them, with the fees they pay. Each transaction is chosen with the
+    /// ancestors it still needs, taking the package paying the highest fee
+    /// rate first, and follows its ancestors in the block.
+    pub fn select(&self, bc: &Blockchain, max_size: usize) -> (Vec<TX>, u64) {
+        let view = self.view(bc);
+        let height = bc.height() + 1;
+        let time = bc.median_time_past();
+
+        // The fee and size of each transaction that may be mined at `height`
+        let mut minable: HashMap<String, (u64, usize)> = HashMap::new();
+        for tx in self.txs.iter() {
+            if let TX::Standard(ref stx) = *tx {
+                if stx.check_maturity(bc, height).is_err() || stx.check_lock_times(bc, height, time).is_err() {
+                    continue;
+                }
+                if let Ok(fee) = stx.fee(&view) {
+                    minable.insert(tx.id(), (fee, stx.size()));
+                }
+            }
+        }
+
+        let mut selected = Vec::new();
+        let mut included: HashSet<String> = HashSet::new();
+        let (mut size, mut fees) = (0, 0u64);
+        loop {
+            // The package paying the highest fee rate that still fits, the
+            // earliest to enter the mempool among equals
+            let mut best: Option<(Vec<String>, u64, usize)> = None;
+            for tx in self.txs.iter() {
+                let txid = tx.id();
+                let stx = match *tx {
+                    TX::Standard(ref stx) if !included.contains(&txid) => stx,
+                    _ => continue,
+                };
+                let mut package: Vec<String> = self.ancestors(stx)
+                    .into_iter()
+                    .filter(|ancestor| !included.contains(ancestor))
+                    .collect();
+                package.push(txid);
+
+                // Transactions with an ancestor that cannot be mined wait
+                let totals = package.iter().try_fold((0u64, 0), |(fee, size), txid| {
+                    let &(tx_fee, tx_size) = minable.get(txid)?;
+                    Some((fee.checked_add(tx_fee)?, size + tx_size))
+                });
+                let (package_fee, package_size) = match totals {
+                    Some(totals) => totals,
+                    None => continue,
+                };
+                if size + package_size > max_size {
+                    continue;
+                }
+                let better = best.as_ref().is_none_or(|&(_, best_fee, best_size)| {
+                    package_fee as u128 * best_size as u128 > best_fee as u128 * package_size as u128
+                });
+                if better {
+                    best = Some((package, package_fee, package_size));
+                }
+            }
+
+            let (mut package, package_fee, package_size) = match best {
+                Some(best) => best,
+                None => break,
+            };
+            size += package_size;
+            fees = fees.saturating_add(package_fee);
+
+            // Add the package with each transaction after its parents
+            while !package.is_empty() {
+                let ready = package
+                    .iter()
+                    .position(|txid| match self.get(txid) {
+                        Some(TX::Standard(stx)) => self.parents(stx).is_subset(&included),
+                        _ => true,
+                    })
+                    .unwrap();
+                let txid = package.swap_remove(ready);
+                selected.push(self.get(&txid).unwrap().clone());
+                included.insert(txid);
+            }
         }
 
-        tx
+        (selected, fees)
     }
 }
 
 
+impl Default for MemPool {
+    fn default() -> MemPool {
+        MemPool::new()
+    }
+}
+
 impl Drop for MemPool {
     fn drop(&mut self) {
         self.save();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constants::{MAX_BLOCK_SIZE, MAX_DESCENDANTS};
+    use script::{Op, Script};
+    use std::mem::ManuallyDrop;
+
+    // An output anyone can spend with an empty unlocking script, so that
+    // the tests need no keys
+    fn output(value: u64) -> TXOutput {
+        TXOutput::with_script(value, Script::new(vec![Op::Push(vec![1])]))
+    }
+
+    fn standard(inputs: &[(&str, usize)], values: &[u64], replaceable: bool) -> TX {
+        let inputs = inputs
+            .iter()
+            .map(|&(txid, vout)| TXInput {
+                txid: txid.to_owned(),
+                vout,
+                signature: None,
+                pubkey: None,
+                schnorr_signature: None,
+                unlock: Some(Script::default()),
+                redeem_script: None,
+                relative_lock_time: 0,
+            })
+            .collect();
+        TX::Standard(StandardTX {
+            inputs,
+            outputs: values.iter().map(|&value| output(value)).collect(),
+            replaceable,
+            lock_time: 0,
+            memo: String::new(),
+        })
+    }
+
+    // Spends the outputs `inputs` to outputs of `values`
+    fn spend(inputs: &[(&str, usize)], values: &[u64]) -> TX {
+        standard(inputs, values, false)
+    }
+
+    // A blockchain with a confirmed output of each of `values`, and the id
+    // of the transaction holding them
+    fn funded(values: &[u64]) -> (ManuallyDrop<Blockchain>, String) {
+        let funding = spend(&[], values);
+        let txid = funding.id();
+        (Blockchain::unchecked(vec![funding]), txid)
+    }
+
+    fn mempool() -> ManuallyDrop<MemPool> {
+        ManuallyDrop::new(MemPool::new())
+    }
+
+    fn assert_rejected(result: Result<()>, expected: Error) {
+        match result {
+            Err(ref err) if err.to_string() == expected.to_string() => {}
+            other => panic!("expected {:?}, got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn accepts_chains_of_unconfirmed_transactions() {
+        let (bc, funding) = funded(&[10]);
+        let mut mp = mempool();
+        let parent = spend(&[(&funding, 0)], &[9]);
+        let child = spend(&[(&parent.id(), 0)], &[8]);
+        mp.push(&bc, parent.clone()).unwrap();
+        mp.push(&bc, child.clone()).unwrap();
+
+        assert_eq!(mp.spender(&parent.id(), 0), Some(&child.id()));
+        assert!(mp.view(&bc).find_transaction(&parent.id()).is_some());
+        assert!(mp.utxo(&bc)[&funding].is_empty());
+    }
+
+    #[test]
+    fn rejects_spends_of_unknown_outputs() {
+        let (bc, funding) = funded(&[10]);
+        let mut mp = mempool();
+        let parent = spend(&[(&funding, 0)], &[9]);
+        let child = spend(&[(&parent.id(), 0)], &[8]);
+        assert_rejected(mp.push(&bc, child), Error::TransactionMissing);
+        assert_rejected(mp.push(&bc, spend(&[(&funding, 1)], &[1])), Error::NoTXOutput);
+    }
+
+    #[test]
+    fn limits_unconfirmed_ancestors() {
+        let (bc, funding) = funded(&[100]);
+        let mut mp = mempool();
+        let mut prev = funding;
+        for i in 0..MAX_ANCESTORS {
+            let tx = spend(&[(&prev, 0)], &[99 - i as u64]);
+            prev = tx.id();
+            mp.push(&bc, tx).unwrap();
+        }
+        assert_rejected(mp.push(&bc, spend(&[(&prev, 0)], &[1])), Error::TooManyAncestors);
+    }
+
+    #[test]
+    fn limits_unconfirmed_descendants() {
+        let (bc, funding) = funded(&[100]);
+        let mut mp = mempool();
+        let root = spend(&[(&funding, 0)], &[1; MAX_DESCENDANTS + 1]);
+        let root_id = root.id();
+        mp.push(&bc, root).unwrap();
+        for vout in 0..MAX_DESCENDANTS {
+            mp.push(&bc, spend(&[(&root_id, vout)], &[1])).unwrap();
+        }
+        let extra = spend(&[(&root_id, MAX_DESCENDANTS)], &[1]);
+        assert_rejected(mp.push(&bc, extra), Error::TooManyDescendants);
+    }
+
+    #[test]
+    fn connected_blocks_remove_confirmed_and_conflicting_transactions() {
+        let (bc, funding) = funded(&[10, 10]);
+        let mut mp = mempool();
+        let parent = spend(&[(&funding, 0)], &[9]);
+        let child = spend(&[(&parent.id(), 0)], &[8]);
+        let conflicted = spend(&[(&funding, 1)], &[9]);
+        let conflicted_child = spend(&[(&conflicted.id(), 0)], &[8]);
+        for tx in [&parent, &child, &conflicted, &conflicted_child] {
+            mp.push(&bc, tx.clone()).unwrap();
+        }
+
+        let block = Block {
+            timestamp: 0,
+            transactions: vec![parent.clone(), spend(&[(&funding, 1)], &[7])],
+            prev_block_hash: bc.last_block_hash.clone(),
+            nonce: 0,
+        };
+        mp.block_connected(&block);
+
+        // The child of a confirmed transaction stays
+        let txids: Vec<String> = mp.transactions().map(|tx| tx.id()).collect();
+        assert_eq!(txids, vec![child.id()]);
+    }
+
+    fn txids(txs: &[TX]) -> Vec<String> {
+        txs.iter().map(|tx| tx.id()).collect()
+    }
+
+    #[test]
+    fn rejects_conflicts_with_unreplaceable_transactions() {
+        let (bc, funding) = funded(&[10]);
+        let mut mp = mempool();
+        mp.push(&bc, spend(&[(&funding, 0)], &[9])).unwrap();
+        let conflict = spend(&[(&funding, 0)], &[1]);
+        assert_rejected(mp.push(&bc, conflict), Error::ConflictingTransaction);
+        let double_spend = spend(&[(&funding, 0), (&funding, 0)], &[1]);
+        assert_rejected(mp.push(&bc, double_spend), Error::ConflictingTransaction);
+    }
+
+    #[test]
+    fn replacements_evict_the_original_and_its_descendants() {
+        let (bc, funding) = funded(&[10]);
+        let mut mp = mempool();
+        let original = standard(&[(&funding, 0)], &[9], true);
+        let child = spend(&[(&original.id(), 0)], &[8]);
+        mp.push(&bc, original.clone()).unwrap();
+        mp.push(&bc, child.clone()).unwrap();
+
+        let replacement = standard(&[(&funding, 0)], &[5], true);
+        mp.push(&bc, replacement.clone()).unwrap();
+        assert_eq!(pooled(&mp), vec![replacement.id()]);
+        let mut dropped = mp.take_dropped();
+        dropped.sort();
+        let mut expected = vec![original.id(), child.id()];
+        expected.sort();
+        assert_eq!(dropped, expected);
+    }
+
+    #[test]
+    fn replacements_must_pay_more_than_they_evict() {
+        let (bc, funding) = funded(&[10]);
+        let mut mp = mempool();
+        let original = standard(&[(&funding, 0)], &[9], true);
+        let child = spend(&[(&original.id(), 0)], &[6]);
+        mp.push(&bc, original.clone()).unwrap();
+
+        // The same fee, then a lower one
+        assert_rejected(mp.push(&bc, standard(&[(&funding, 0)], &[8, 1], true)), Error::InsufficientReplacementFee);
+        assert_rejected(mp.push(&bc, standard(&[(&funding, 0)], &[10], true)), Error::InsufficientReplacementFee);
+
+        // A higher fee rate than the original, but less in total than it
+        // and its child pay
+        mp.push(&bc, child).unwrap();
+        assert_rejected(mp.push(&bc, standard(&[(&funding, 0)], &[7], true)), Error::InsufficientReplacementFee);
+        assert_eq!(mp.transactions().count(), 2);
+    }
+
+    #[test]
+    fn replacements_may_not_spend_what_they_evict() {
+        let (bc, funding) = funded(&[10, 10]);
+        let mut mp = mempool();
+        let original = standard(&[(&funding, 0)], &[9, 1], true);
+        mp.push(&bc, original.clone()).unwrap();
+        let replacement = standard(&[(&funding, 0), (&original.id(), 1)], &[1], true);
+        assert_rejected(mp.push(&bc, replacement), Error::ConflictingTransaction);
+    }
+
+    #[test]
+    fn selects_packages_by_ancestor_fee_rate() {
+        let (bc, funding) = funded(&[10, 10, 10]);
+        let mut mp = mempool();
+        let low = spend(&[(&funding, 0)], &[9]);
+        let parent = spend(&[(&funding, 1)], &[10]);
+        let child = spend(&[(&parent.id(), 0)], &[2]);
+        let middle = spend(&[(&funding, 2)], &[7]);
+        for tx in [&low, &parent, &child, &middle] {
+            mp.push(&bc, tx.clone()).unwrap();
+        }
+
+        // The child pays for its parent, and both come first
+        let (selected, fees) = mp.select(&bc, MAX_BLOCK_SIZE);
+        assert_eq!(txids(&selected), txids(&[parent, child, middle.clone(), low.clone()]));
+        assert_eq!(fees, 12);
+
+        // Only what fits is selected
+        let (selected, fees) = mp.select(&bc, size(&middle));
+        assert_eq!(txids(&selected), txids(&[middle]));
+        assert_eq!(fees, 3);
+    }
+
+    // The ids of the transactions in the mempool, in the order they entered
+    fn pooled(mp: &MemPool) -> Vec<String> {
+        mp.transactions().map(|tx| tx.id()).collect()
+    }
+
+    fn size(tx: &TX) -> usize {
+        match *tx {
+            TX::Standard(ref stx) => stx.size(),
+            TX::Coinbase(_) => 0,
+        }
+    }
+
+    #[test]
+    fn trims_the_lowest_fee_rates_to_the_size_cap() {
+        let (bc, funding) = funded(&[10, 10, 10]);
+        let mut mp = mempool();
+        let low = spend(&[(&funding, 0)], &[9]);
+        let low_child = spend(&[(&low.id(), 0)], &[1]);
+        let high = spend(&[(&funding, 1)], &[5]);
+        for tx in [&low, &low_child, &high] {
+            mp.push(&bc, tx.clone()).unwrap();
+        }
+
+        // The lowest fee rate goes first, with its descendants, even one
+        // paying a higher rate
+        mp.max_size = size(&high) + size(&low_child);
+        mp.trim(&bc);
+        assert_eq!(pooled(&mp), vec![high.id()]);
+        assert_eq!(mp.take_dropped().len(), 2);
+        mp.max_size = size(&high);
+
+        // A transaction evicted on arrival leaves the mempool unchanged
+        let lower = spend(&[(&funding, 2)], &[8]);
+        assert_rejected(mp.push(&bc, lower), Error::MemPoolFull);
+        assert_eq!(pooled(&mp), vec![high.id()]);
+        assert!(mp.take_dropped().is_empty());
+    }
+
+    #[test]
+    fn expires_old_transactions_with_their_descendants() {
+        let (bc, funding) = funded(&[10, 10]);
+        let mut mp = mempool();
+        let old = spend(&[(&funding, 0)], &[9]);
+        let child = spend(&[(&old.id(), 0)], &[8]);
+        let recent = spend(&[(&funding, 1)], &[9]);
+        for tx in [&old, &child, &recent] {
+            mp.push(&bc, tx.clone()).unwrap();
+        }
+
+        let expired = now() - mp.expiry - 1;
+        mp.entry_times.insert(old.id(), expired);
+        mp.expire();
+        assert_eq!(pooled(&mp), vec![recent.id()]);
+        assert_eq!(mp.take_dropped().len(), 2);
+    }
+
+    #[test]
+    fn disconnected_blocks_return_their_transactions() {
+        let (mut bc, funding) = funded(&[10, 10]);
+        let mut mp = mempool();
+        let confirmed = spend(&[(&funding, 0)], &[9]);
+        let coinbase = TX::Coinbase(CoinbaseTX::new(&Address::from_script(&Script::default()), 1));
+        let block = Block::mine(vec![confirmed.clone(), coinbase], bc.last_block_hash.clone());
+        bc.add_block(block).unwrap();
+        let child = spend(&[(&confirmed.id(), 0)], &[8]);
+        mp.push(&bc, child.clone()).unwrap();
+
+        let block = bc.disconnect_tip().unwrap();
+        mp.block_disconnected(&bc, &block);
+        let mut expected = txids(&[child, confirmed]);
+        let mut pooled = pooled(&mp);
+        expected.sort();
+        pooled.sort();
+        assert_eq!(pooled, expected);
+        assert!(mp.take_dropped().is_empty());
+    }
+}
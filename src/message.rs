@@ -0,0 +1,89 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use secp256k1::{Message, RecoverableSignature, RecoveryId, Secp256k1};
+use address::Address;
+use error::{Error, Result};
+use wallet::Wallet;
+
+// Prepended to messages so a signed message can never be a valid
+// transaction signature
+const MAGIC: &str = "Raicoin Signed Message:\n";
+// Added to the recovery id in the first byte of a signature, marking the
+// public key as compressed
+const HEADER_BASE: u8 = 27 + 4;
+
+fn message_hash(message: &str) -> Message {
+    let mut first = [0; 32];
+    let mut hash = Sha256::new();
+    hash.input_str(MAGIC);
+    hash.input_str(message);
+    hash.result(&mut first);
+
+    let mut second = [0; 32];
+    let mut hash = Sha256::new();
+    hash.input(&first);
+    hash.result(&mut second);
+
+    Message::from_slice(&second).unwrap()
+}
+
+/// Signs `message` with the wallet's secret key, returning a Base64
+/// signature from which the public key can be recovered.
+pub fn sign(wallet: &Wallet, message: &str) -> Result<String> {
+    let secp = Secp256k1::new();
+    let signature = secp.sign_recoverable(&message_hash(message), wallet.secret_key()?)
+        .unwrap();
+    let (recovery_id, compact) = signature.serialize_compact(&secp);
+
+    let mut data = vec![HEADER_BASE + recovery_id.to_i32() as u8];
+    data.extend_from_slice(&compact);
+    Ok(data.to_base64(STANDARD))
+}
+
+/// Whether `signature` was made by the key of `address` over `message`.
+pub fn verify(address: &Address, message: &str, signature: &str) -> Result<bool> {
+    let data = signature.from_base64().map_err(|_| Error::InvalidSignature)?;
+    if data.len() != 65 || data[0] < HEADER_BASE {
+        return Err(Error::InvalidSignature);
+    }
+
+    let secp = Secp256k1::new();
+    let recovery_id = RecoveryId::from_i32((data[0] - HEADER_BASE) as i32)
+        .map_err(|_| Error::InvalidSignature)?;
+    let signature = RecoverableSignature::from_compact(&secp, &data[1..], recovery_id)
+        .map_err(|_| Error::InvalidSignature)?;
+
+    // The Schnorr and legacy forms of an address hold the same key
+    Ok(match secp.recover(&message_hash(message), &signature) {
+        Ok(public_key) => {
+            let signer = Address::from_public_key(&public_key, address.hash_type());
+            !address.is_script() && signer.pubkey_hash() == address.pubkey_hash()
+        }
+        Err(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_the_legacy_and_schnorr_addresses_of_the_signer() {
+        let mut wallet = Wallet::new();
+        let signature = sign(&wallet, "hello").unwrap();
+        assert!(verify(&wallet.address(), "hello", &signature).unwrap());
+
+        wallet.schnorr = true;
+        assert!(wallet.address().is_schnorr());
+        assert!(verify(&wallet.address(), "hello", &signature).unwrap());
+    }
+
+    #[test]
+    fn rejects_other_messages_and_keys() {
+        let wallet = Wallet::new();
+        let signature = sign(&wallet, "hello").unwrap();
+        assert!(!verify(&wallet.address(), "goodbye", &signature).unwrap());
+        assert!(!verify(&Wallet::new().address(), "hello", &signature).unwrap());
+    }
+}
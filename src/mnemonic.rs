@@ -0,0 +1,177 @@
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::{Sha256, Sha512};
+use encryption;
+use error::{Error, Result};
+
+// The BIP39 English wordlist, one word per line in sorted order
+const WORDLIST: &str = include_str!("wordlist.txt");
+const BITS_PER_WORD: usize = 11;
+const SEED_SIZE: usize = 64;
+const PBKDF2_ROUNDS: u32 = 2048;
+
+fn wordlist() -> Vec<&'static str> {
+    WORDLIST.lines().collect()
+}
+
+fn valid_word_count(words: usize) -> bool {
+    (12..=24).contains(&words) && words.is_multiple_of(3)
+}
+
+/// Encodes entropy of 16 to 32 bytes, in steps of 4, as a phrase of words
+/// ending with a checksum of the entropy.
+pub fn from_entropy(entropy: &[u8]) -> String {
+    let mut hash = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(entropy);
+    hasher.result(&mut hash);
+
+    // One checksum bit for every 32 bits of entropy
+    let checksum_bits = entropy.len() / 4;
+    let bits: Vec<bool> = entropy
+        .iter()
+        .chain(hash.iter())
+        .flat_map(|&byte| (0..8).rev().map(move |i| byte & (1 << i) != 0))
+        .take(entropy.len() * 8 + checksum_bits)
+        .collect();
+
+    let words = wordlist();
+    bits.chunks(BITS_PER_WORD)
+        .map(|chunk| {
+            let index = chunk.iter().fold(0, |acc, &bit| (acc << 1) | bit as usize);
+            words[index]
+        })
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Generates a random phrase of `words` words, which must be 12, 15, 18, 21
+/// or 24.
+pub fn generate(words: usize) -> Result<String> {
+    if !valid_word_count(words) {
+        return Err(Error::InvalidMnemonic);
+    }
+    Ok(from_entropy(&encryption::random_bytes(words * 4 / 3)))
+}
+
+/// Decodes a phrase back to its entropy, checking every word is in the
+/// wordlist and the checksum matches.
+pub fn to_entropy(phrase: &str) -> Result<Vec<u8>> {
+    let words = wordlist();
+    let indexes = phrase
+        .split_whitespace()
+        .map(|word| words.binary_search(&word).map_err(|_| Error::InvalidMnemonic))
+        .collect::<Result<Vec<usize>>>()?;
+    if !valid_word_count(indexes.len()) {
+        return Err(Error::InvalidMnemonic);
+    }
+
+    let bits: Vec<bool> = indexes
+        .iter()
+        .flat_map(|&index| (0..BITS_PER_WORD).rev().map(move |i| index & (1 << i) != 0))
+        .collect();
+    let entropy: Vec<u8> = bits
+        .chunks(8)
+        .take(indexes.len() * 4 / 3)
+        .map(|chunk| chunk.iter().fold(0, |acc, &bit| (acc << 1) | bit as u8))
+        .collect();
+
+    // Re-encoding the entropy recomputes the checksum
+    if from_entropy(&entropy) != normalize(phrase) {
+        return Err(Error::InvalidMnemonic);
+    }
+    Ok(entropy)
+}
+
+/// Collapses the whitespace between the words of a phrase.
+pub fn normalize(phrase: &str) -> String {
+    phrase.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Stretches a valid phrase and an optional passphrase into a 64 byte seed.
+/// A different passphrase gives a different, equally valid, seed.
+pub fn to_seed(phrase: &str, passphrase: &str) -> Result<Vec<u8>> {
+    to_entropy(phrase)?;
+
+    let salt = format!("mnemonic{}", passphrase);
+    let mut mac = Hmac::new(Sha512::new(), normalize(phrase).as_bytes());
+    let mut seed = vec![0; SEED_SIZE];
+    pbkdf2(&mut mac, salt.as_bytes(), PBKDF2_ROUNDS, &mut seed);
+
+    Ok(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    // BIP39 English test vectors: entropy, phrase and the seed with the
+    // passphrase "TREZOR"
+    const VECTORS: &[(&str, &str, &str)] = &[
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "80808080808080808080808080808080",
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+            "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+        ),
+        (
+            "9e885d952ad362caeb4efe34a8e91bd2",
+            "ozone drill grab fiber curtain grace pudding thank cruise elder eight picnic",
+            "274ddc525802f7c828d8ef7ddbcdc5304e87ac3535913611fbbfa986d0c9e5476c91689f9c8a54fd55bd38606aa6a8595ad213d4c9c9f9aca3fb217069a41028",
+        ),
+        (
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art",
+            "bda85446c68413707090a52022edd26a1c9462295029f2e60cd7c4f2bbd3097170af7a4d73245cafa9c3cca8d561a7c3de6f5d4a10be8ed2a5e608d68f92fcc8",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+            "dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e1613912f0a5b694407be899ffd31ed3992c456cdf60f5d4564b8ba3f05a69890ad",
+        ),
+    ];
+
+    #[test]
+    fn encodes_the_entropy_of_the_vectors() {
+        for &(entropy, phrase, _) in VECTORS {
+            assert_eq!(from_entropy(&entropy.from_hex().unwrap()), phrase);
+            assert_eq!(to_entropy(phrase).unwrap().to_hex(), entropy);
+        }
+    }
+
+    #[test]
+    fn derives_the_seeds_of_the_vectors() {
+        for &(_, phrase, seed) in VECTORS {
+            assert_eq!(to_seed(phrase, "TREZOR").unwrap().to_hex(), seed);
+        }
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon";
+        assert!(matches!(to_entropy(phrase), Err(Error::InvalidMnemonic)));
+        assert!(matches!(to_seed(phrase, "TREZOR"), Err(Error::InvalidMnemonic)));
+    }
+
+    #[test]
+    fn rejects_unknown_words_and_counts() {
+        assert!(to_entropy("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon bitcoin").is_err());
+        assert!(to_entropy("abandon abandon abandon about").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use rustc_serialize::hex::{FromHex, ToHex};
+use secp256k1::{Secp256k1, Signature};
+use secp256k1::key::PublicKey;
+use coinselect::Coin;
+use error::{Error, Result};
+use schnorr::{self, SchnorrSignature};
+use script::{Op, Script};
+use transaction::{StandardTX, TXInput, TXOptions, TXOutput, TX};
+use wallet::{hash_public_key, serialize_public_key, Wallet, Wallets};
+
+/// A transaction passed between machines to collect the signatures of its
+/// inputs before it is sent, so that it can be built where only the
+/// addresses are known and signed where the keys are.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PartialTX {
+    /// The transaction, whose inputs are unlocked once it is finalized.
+    pub tx: StandardTX,
+    pub inputs: Vec<PartialInput>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PartialInput {
+    /// The output the input spends.
+    pub output: TXOutput,
+    /// The multisig script of an output locked to a script hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redeem_script: Option<Script>,
+    /// Hex signatures collected so far, keyed by the hex public key that
+    /// made them.
+    #[serde(default)]
+    pub signatures: BTreeMap<String, String>,
+}
+
+impl PartialInput {
+    // The wallets holding keys that can sign for the input
+    fn signers<'a>(&self, wallets: &'a Wallets) -> Vec<&'a Wallet> {
+        match self.redeem_script {
+            Some(ref redeem_script) => {
+                let secp = Secp256k1::new();
+                let public_keys = match redeem_script.parse_multisig() {
+                    Some((_, public_keys)) => public_keys,
+                    None => return Vec::new(),
+                };
+                public_keys
+                    .iter()
+                    .filter_map(|public_key| PublicKey::from_slice(&secp, public_key).ok())
+                    .filter_map(|public_key| wallets.find_by_public_key(&public_key))
+                    .collect()
+            }
+            None => {
+                self.output
+                    .address()
+                    .ok()
+                    .and_then(|address| wallets.get(&address))
+                    .into_iter()
+                    .collect()
+            }
+        }
+    }
+
+    // A collected signature by the key the output is locked to
+    fn key_signature(&self) -> Option<(PublicKey, Vec<u8>)> {
+        let secp = Secp256k1::new();
+        self.signatures.iter().find_map(|(public_key, signature)| {
+            let public_key = PublicKey::from_slice(&secp, &public_key.from_hex().ok()?).ok()?;
+            if hash_public_key(&public_key, self.output.hash_type) == self.output.pubkey_hash {
+                Some((public_key, signature.from_hex().ok()?))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl PartialTX {
+    /// An unsigned transaction spending each coin to `outputs`, with the
+    /// multisig script of coins locked to a script hash.
+    pub fn new(
+        coins: Vec<(Coin, Option<Script>)>,
+        outputs: Vec<TXOutput>,
+        options: &TXOptions,
+    ) -> PartialTX {
+        let mut inputs = Vec::new();
+        let mut partial_inputs = Vec::new();
+        for (coin, redeem_script) in coins {
+            let mut input = TXInput::unsigned(&coin);
+            input.redeem_script = redeem_script.clone();
+            inputs.push(input);
+            partial_inputs.push(PartialInput {
+                output: coin.output,
+                redeem_script,
+                signatures: BTreeMap::new(),
+            });
+        }
+
+        PartialTX {
+            tx: StandardTX {
+                inputs,
+                outputs,
+                replaceable: options.replaceable,
+                lock_time: options.lock_time,
+                memo: options.memo.clone(),
+            },
+            inputs: partial_inputs,
+        }
+    }
+
+    /// Signs every input it can with the keys held in `wallets`, returning
+    /// the number of signatures added.
+    pub fn sign(&mut self, wallets: &Wallets) -> Result<usize> {
+        let mut added = 0;
+        for (index, input) in self.inputs.iter_mut().enumerate() {
+            let digest = self.tx.signature_hash(index, &input.output);
+            for wallet in input.signers(wallets) {
+                let public_key = serialize_public_key(&wallet.public_key).to_hex();
+                let signature = if input.output.is_schnorr() {
+                    schnorr::sign(&digest, wallet.secret_key()?).serialize().to_hex()
+                } else {
+                    wallet.sign_digest(&digest)?.to_hex()
+                };
+                if input.signatures.insert(public_key, signature).is_none() {
+                    added += 1;
+                }
+            }
+        }
+
+        Ok(added)
+    }
+
+    // The id of the transaction without the data unlocking its inputs
+    fn unsigned_id(&self) -> String {
+        let mut tx = self.tx.clone();
+        for input in tx.inputs.iter_mut() {
+            input.signature = None;
+            input.schnorr_signature = None;
+            input.pubkey = None;
+            input.unlock = None;
+        }
+        TX::Standard(tx).id()
+    }
+
+    /// Adds the signatures collected in `other`, a copy of the same
+    /// transaction.
+    pub fn combine(&mut self, other: PartialTX) -> Result<()> {
+        if self.unsigned_id() != other.unsigned_id() || self.inputs.len() != other.inputs.len() {
+            return Err(Error::PartialTXMismatch);
+        }
+        for (input, other) in self.inputs.iter_mut().zip(other.inputs) {
+            input.signatures.extend(other.signatures);
+        }
+
+        Ok(())
+    }
+
+    /// Unlocks every input of the transaction with the signatures
+    /// collected: the signature of the key of a public key hash, or as many
+    /// signatures as a multisig script requires, in the order of its keys.
+    pub fn finalize(&mut self) -> Result<()> {
+        let secp = Secp256k1::new();
+        for (tx_input, input) in self.tx.inputs.iter_mut().zip(self.inputs.iter()) {
+            match input.redeem_script {
+                Some(ref redeem_script) => {
+                    let (required, public_keys) = redeem_script
+                        .parse_multisig()
+                        .ok_or(Error::InvalidMultisig)?;
+
+                    let signatures: Vec<Op> = public_keys
+                        .iter()
+                        .filter_map(|public_key| input.signatures.get(&public_key.to_hex()))
+                        .filter_map(|signature| signature.from_hex().ok())
+                        .take(required)
+                        .map(Op::Push)
+                        .collect();
+                    if signatures.len() < required {
+                        return Err(Error::MissingSignatures);
+                    }
+                    tx_input.unlock = Some(Script::new(signatures));
+                }
+                None => {
+                    // Outputs locked by other scripts need their own
+                    // unlocking scripts
+                    if input.output.script.is_some() {
+                        return Err(Error::InvalidPartialTX);
+                    }
+                    let (public_key, signature) = input.key_signature()
+                        .ok_or(Error::MissingSignatures)?;
+                    if input.output.is_schnorr() {
+                        tx_input.schnorr_signature = Some(SchnorrSignature::from_slice(&signature)?);
+                    } else {
+                        let signature = Signature::from_der(&secp, &signature)
+                            .map_err(|_| Error::InvalidSignature)?;
+                        tx_input.signature = Some(signature);
+                    }
+                    tx_input.pubkey = Some(public_key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The finalized transaction, ready to be sent.
+    pub fn extract(&self) -> Result<StandardTX> {
+        let unlocked = self.tx.inputs.iter().all(|input| {
+            let signed = input.signature.is_some() || input.schnorr_signature.is_some();
+            input.unlock.is_some() || (signed && input.pubkey.is_some())
+        });
+        if !unlocked {
+            return Err(Error::MissingSignatures);
+        }
+
+        Ok(self.tx.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
+    use address::Address;
+    use blockchain::Blockchain;
+    use serde_json;
+
+    // Wallets holding a single new key each
+    fn signer() -> ManuallyDrop<Wallets> {
+        let mut wallets = Wallets::empty();
+        wallets.add(Wallet::new()).unwrap();
+        wallets
+    }
+
+    fn public_key(wallets: &Wallets) -> Vec<u8> {
+        let address = wallets.addresses().pop().unwrap();
+        serialize_public_key(&wallets.get(&address).unwrap().public_key)
+    }
+
+    // A blockchain with an output of 50 locked to a `required` of `signers`
+    // multisig script, and a partial transaction spending it
+    fn multisig_spend(required: usize, signers: &[&Wallets]) -> (ManuallyDrop<Blockchain>, PartialTX) {
+        let public_keys: Vec<Vec<u8>> = signers.iter().map(|wallets| public_key(wallets)).collect();
+        let redeem_script = Script::multisig(required, &public_keys);
+        let funding = TX::Standard(StandardTX {
+            inputs: vec![],
+            outputs: vec![TXOutput::new(50, &Address::from_script(&redeem_script))],
+            replaceable: false,
+            lock_time: 0,
+            memo: String::new(),
+        });
+        let coin = Coin {
+            txid: funding.id(),
+            vout: 0,
+            output: funding.outputs()[0].clone(),
+        };
+        let payee = Address::from_script(&Script::new(vec![Op::Push(vec![1])]));
+        let ptx = PartialTX::new(
+            vec![(coin, Some(redeem_script))],
+            vec![TXOutput::new(40, &payee)],
+            &TXOptions::default(),
+        );
+        (Blockchain::unchecked(vec![funding]), ptx)
+    }
+
+    // Passes a partial transaction through the file format, as between
+    // machines
+    fn pass(ptx: &PartialTX) -> PartialTX {
+        serde_json::from_str(&serde_json::to_string(ptx).unwrap()).unwrap()
+    }
+
+    fn assert_missing_signatures<T>(result: Result<T>) {
+        match result {
+            Err(Error::MissingSignatures) => {}
+            Err(err) => panic!("expected MissingSignatures, got {:?}", err),
+            Ok(_) => panic!("expected MissingSignatures"),
+        }
+    }
+
+    #[test]
+    fn signatures_combine_into_a_multisig_spend() {
+        let (first, second, third) = (signer(), signer(), signer());
+        let (bc, ptx) = multisig_spend(2, &[&first, &second, &third]);
+
+        // The first and last keys sign separate copies
+        let mut from_first = pass(&ptx);
+        assert_eq!(from_first.sign(&first).unwrap(), 1);
+        let mut from_third = pass(&ptx);
+        assert_eq!(from_third.sign(&third).unwrap(), 1);
+        assert_eq!(from_third.sign(&third).unwrap(), 0);
+
+        let mut combined = pass(&from_third);
+        combined.combine(pass(&from_first)).unwrap();
+        assert_eq!(combined.inputs[0].signatures.len(), 2);
+        combined.finalize().unwrap();
+
+        let tx = combined.extract().unwrap();
+        assert_eq!(tx.inputs[0].unlock.as_ref().map(|unlock| unlock.ops.len()), Some(2));
+        tx.verify(&*bc).unwrap();
+    }
+
+    #[test]
+    fn finalizing_needs_the_required_signatures() {
+        let (first, second, third) = (signer(), signer(), signer());
+        let (bc, mut ptx) = multisig_spend(2, &[&first, &second, &third]);
+        assert_missing_signatures(ptx.extract());
+
+        ptx.sign(&second).unwrap();
+        assert_missing_signatures(ptx.finalize());
+        assert_missing_signatures(ptx.extract());
+
+        // Wallets without any of the keys add nothing
+        assert_eq!(ptx.sign(&signer()).unwrap(), 0);
+        assert_missing_signatures(ptx.finalize());
+
+        ptx.sign(&first).unwrap();
+        ptx.finalize().unwrap();
+        ptx.extract().unwrap().verify(&*bc).unwrap();
+    }
+
+    #[test]
+    fn too_few_signatures_do_not_unlock_a_multisig_output() {
+        let (first, second, third) = (signer(), signer(), signer());
+        let (bc, mut ptx) = multisig_spend(2, &[&first, &second, &third]);
+        ptx.sign(&first).unwrap();
+
+        // Unlocked by hand, bypassing the check in finalize
+        let signature = ptx.inputs[0].signatures.values().next().unwrap().from_hex().unwrap();
+        ptx.tx.inputs[0].unlock = Some(Script::new(vec![Op::Push(signature)]));
+        assert!(ptx.tx.verify(&*bc).is_err());
+    }
+
+    #[test]
+    fn combines_copies_of_the_same_transaction_only() {
+        let (first, second) = (signer(), signer());
+        let (_, mut ptx) = multisig_spend(1, &[&first, &second]);
+        let mut other = ptx.clone();
+        other.tx.outputs[0].value = 30;
+
+        match ptx.combine(other) {
+            Err(Error::PartialTXMismatch) => {}
+            other => panic!("expected PartialTXMismatch, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+use std::result;
+use rustc_serialize::hex::{FromHex, ToHex};
+use secp256k1::{schnorr, ContextFlag, Message, Secp256k1};
+use secp256k1::key::{PublicKey, SecretKey};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use error::{Error, Result};
+use verify;
+
+// The size in bytes of a serialized signature: the x coordinate of the
+// nonce point followed by the scalar
+const SIGNATURE_SIZE: usize = 64;
+
+/// A Schnorr signature, which signs inputs spending outputs of the Schnorr
+/// version. Serialized as hex.
+#[derive(Clone, Copy)]
+pub struct SchnorrSignature(schnorr::Signature);
+
+impl SchnorrSignature {
+    pub fn from_slice(data: &[u8]) -> Result<SchnorrSignature> {
+        if data.len() != SIGNATURE_SIZE {
+            return Err(Error::InvalidSignature);
+        }
+        Ok(SchnorrSignature(schnorr::Signature::deserialize(data)))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        self.0.serialize()
+    }
+}
+
+impl fmt::Debug for SchnorrSignature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SchnorrSignature({})", self.serialize().to_hex())
+    }
+}
+
+impl Serialize for SchnorrSignature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.serialize().to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for SchnorrSignature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<SchnorrSignature, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let data = s.from_hex().map_err(de::Error::custom)?;
+        SchnorrSignature::from_slice(&data).map_err(|err: Error| de::Error::custom(err))
+    }
+}
+
+/// Signs the 32 byte `digest` with `secret_key`.
+pub fn sign(digest: &[u8], secret_key: &SecretKey) -> SchnorrSignature {
+    let secp = Secp256k1::with_caps(ContextFlag::SignOnly);
+    let msg = Message::from_slice(digest).unwrap();
+    SchnorrSignature(secp.sign_schnorr(&msg, secret_key).unwrap())
+}
+
+/// Whether `signature` signs the 32 byte `digest` by `public_key`. Each
+/// signature is checked on its own: the `schnorr` module of secp256k1 0.7
+/// has no multi-scalar multiplication, and a batch equation built from its
+/// single point operations would be slower than checking them one by one.
+pub fn verify(digest: &[u8], signature: &SchnorrSignature, public_key: &PublicKey) -> bool {
+    let msg = Message::from_slice(digest).unwrap();
+    verify::context().verify_schnorr(&msg, &signature.0, public_key).is_ok()
+}
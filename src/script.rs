@@ -0,0 +1,730 @@
+use std::fmt;
+use std::result;
+use std::str::FromStr;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use constants::{
+    MAX_MULTISIG_KEYS, MAX_SCRIPT_ELEMENT_SIZE, MAX_SCRIPT_NUM_SIZE, MAX_SCRIPT_OPS,
+    MAX_SCRIPT_SIGOPS, MAX_SCRIPT_SIZE, MAX_STACK_SIZE,
+};
+use error::{Error, Result};
+use wallet::hash160;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// Pushes data onto the stack.
+    Push(Vec<u8>),
+    Dup,
+    Drop,
+    Swap,
+    Sha256,
+    Hash160,
+    Equal,
+    EqualVerify,
+    Verify,
+    /// Fails immediately, making an output unspendable.
+    Return,
+    CheckSig,
+    CheckSigVerify,
+    /// Checks that M signatures match M of N public keys, in the order of
+    /// the keys.
+    CheckMultiSig,
+    If,
+    NotIf,
+    Else,
+    EndIf,
+    Add,
+    Sub,
+    Not,
+    NumEqual,
+    LessThan,
+    GreaterThan,
+    /// Fails unless the transaction's lock time is at least the number on
+    /// top of the stack, which is left in place.
+    CheckLockTimeVerify,
+    /// Fails unless the input's relative lock time is at least the number
+    /// on top of the stack, which is left in place.
+    CheckSequenceVerify,
+}
+
+/// A program that locks an output, or unlocks it when run before the
+/// locking script. Written as a sequence of operation names, `0x`-prefixed
+/// hex data and decimal numbers.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Script {
+    pub ops: Vec<Op>,
+}
+
+/// What the interpreter needs to know about the spending transaction.
+pub trait Checker {
+    /// Whether `signature` is a valid signature of the transaction by
+    /// `public_key`, both as serialized on the stack.
+    fn check_signature(&self, signature: &[u8], public_key: &[u8]) -> bool;
+    /// Whether the transaction cannot be mined before `lock_time`.
+    fn check_lock_time(&self, lock_time: u64) -> bool;
+    /// Whether the input cannot be mined before `relative_lock_time` has
+    /// passed since the output it spends confirmed.
+    fn check_relative_lock_time(&self, relative_lock_time: u64) -> bool;
+}
+
+const NAMES: &[(&str, Op)] = &[
+    ("DUP", Op::Dup),
+    ("DROP", Op::Drop),
+    ("SWAP", Op::Swap),
+    ("SHA256", Op::Sha256),
+    ("HASH160", Op::Hash160),
+    ("EQUAL", Op::Equal),
+    ("EQUALVERIFY", Op::EqualVerify),
+    ("VERIFY", Op::Verify),
+    ("RETURN", Op::Return),
+    ("CHECKSIG", Op::CheckSig),
+    ("CHECKSIGVERIFY", Op::CheckSigVerify),
+    ("CHECKMULTISIG", Op::CheckMultiSig),
+    ("IF", Op::If),
+    ("NOTIF", Op::NotIf),
+    ("ELSE", Op::Else),
+    ("ENDIF", Op::EndIf),
+    ("ADD", Op::Add),
+    ("SUB", Op::Sub),
+    ("NOT", Op::Not),
+    ("NUMEQUAL", Op::NumEqual),
+    ("LESSTHAN", Op::LessThan),
+    ("GREATERTHAN", Op::GreaterThan),
+    ("CHECKLOCKTIMEVERIFY", Op::CheckLockTimeVerify),
+    ("CHECKSEQUENCEVERIFY", Op::CheckSequenceVerify),
+];
+
+/// Encodes a number as the shortest little-endian two's complement bytes,
+/// with zero as no bytes at all.
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut bytes = n.to_le_bytes().to_vec();
+    while bytes.len() > 1 {
+        let last = bytes[bytes.len() - 1];
+        let sign = bytes[bytes.len() - 2] & 0x80;
+        if (last == 0x00 && sign == 0) || (last == 0xff && sign != 0) {
+            bytes.pop();
+        } else {
+            break;
+        }
+    }
+    if n == 0 {
+        bytes.clear();
+    }
+    bytes
+}
+
+/// Decodes a number encoded by `encode_num`.
+pub fn decode_num(bytes: &[u8]) -> Result<i64> {
+    if bytes.len() > MAX_SCRIPT_NUM_SIZE {
+        return Err(Error::ScriptFailed);
+    }
+    if bytes.is_empty() {
+        return Ok(0);
+    }
+    // Sign-extend to eight bytes
+    let fill = if bytes[bytes.len() - 1] & 0x80 != 0 { 0xff } else { 0x00 };
+    let mut full = [fill; 8];
+    full[..bytes.len()].copy_from_slice(bytes);
+    Ok(i64::from_le_bytes(full))
+}
+
+// Pops a number between 0 and `max`
+fn pop_count(stack: &mut Vec<Vec<u8>>, max: usize) -> Result<usize> {
+    let n = decode_num(&stack.pop().ok_or(Error::ScriptFailed)?)?;
+    if n < 0 || n as usize > max {
+        return Err(Error::ScriptFailed);
+    }
+    Ok(n as usize)
+}
+
+// Pops `n` items, returning them in the order they were pushed
+fn pop_items(stack: &mut Vec<Vec<u8>>, n: usize) -> Result<Vec<Vec<u8>>> {
+    if stack.len() < n {
+        return Err(Error::ScriptFailed);
+    }
+    let at = stack.len() - n;
+    Ok(stack.split_off(at))
+}
+
+fn is_true(bytes: &[u8]) -> bool {
+    bytes.iter().any(|&byte| byte != 0)
+}
+
+fn encode_bool(b: bool) -> Vec<u8> {
+    encode_num(b as i64)
+}
+
+impl Script {
+    pub fn new(ops: Vec<Op>) -> Script {
+        Script { ops }
+    }
+
+    /// The script locking an output to `required` signatures from
+    /// `public_keys`, given serialized.
+    pub fn multisig(required: usize, public_keys: &[Vec<u8>]) -> Script {
+        let mut ops = vec![Op::Push(encode_num(required as i64))];
+        ops.extend(public_keys.iter().cloned().map(Op::Push));
+        ops.push(Op::Push(encode_num(public_keys.len() as i64)));
+        ops.push(Op::CheckMultiSig);
+        Script::new(ops)
+    }
+
+    /// The number of signatures required and the public keys of a multisig
+    /// script, or `None` if the script is not one.
+    pub fn parse_multisig(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        let count = |op: &Op| match *op {
+            Op::Push(ref data) => decode_num(data).ok().filter(|&n| n >= 0).map(|n| n as usize),
+            _ => None,
+        };
+
+        let (last, ops) = self.ops.split_last()?;
+        let (total, ops) = ops.split_last()?;
+        let (required, keys) = ops.split_first()?;
+        if *last != Op::CheckMultiSig {
+            return None;
+        }
+        let (required, total) = (count(required)?, count(total)?);
+        let keys = keys.iter()
+            .map(|op| match *op {
+                Op::Push(ref data) => Some(data.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if keys.len() != total || required > total {
+            return None;
+        }
+
+        Some((required, keys))
+    }
+
+    /// The hash that outputs locked to the script are paid to: the HASH160
+    /// of its written form.
+    pub fn hash(&self) -> [u8; 20] {
+        hash160(self.to_string().as_bytes())
+    }
+
+    /// Whether the script only pushes data.
+    pub fn is_push_only(&self) -> bool {
+        self.ops.iter().all(|op| matches!(*op, Op::Push(_)))
+    }
+
+    /// One byte per operation plus the pushed data.
+    pub fn size(&self) -> usize {
+        self.ops
+            .iter()
+            .map(|op| match *op {
+                Op::Push(ref data) => 1 + data.len(),
+                _ => 1,
+            })
+            .sum()
+    }
+
+    fn check_size(&self) -> Result<()> {
+        let ops = self.ops.iter().filter(|op| !matches!(**op, Op::Push(_))).count();
+        if ops > MAX_SCRIPT_OPS || self.size() > MAX_SCRIPT_SIZE {
+            return Err(Error::ScriptLimitExceeded);
+        }
+        Ok(())
+    }
+
+    // Runs the script on `stack`, adding the signature checks it runs to
+    // `sigops`
+    fn run(&self, stack: &mut Vec<Vec<u8>>, checker: &dyn Checker, sigops: &mut usize) -> Result<()> {
+        use self::Error::{ScriptFailed, ScriptLimitExceeded};
+
+        self.check_size()?;
+
+        // Whether each enclosing IF branch is being executed
+        let mut branches: Vec<bool> = Vec::new();
+
+        for op in self.ops.iter() {
+            let executing = branches.iter().all(|&b| b);
+            match *op {
+                Op::If | Op::NotIf => {
+                    let mut branch = false;
+                    if executing {
+                        branch = is_true(&stack.pop().ok_or(ScriptFailed)?);
+                        if *op == Op::NotIf {
+                            branch = !branch;
+                        }
+                    }
+                    branches.push(branch);
+                    continue;
+                }
+                Op::Else => {
+                    let branch = branches.last_mut().ok_or(ScriptFailed)?;
+                    *branch = !*branch;
+                    continue;
+                }
+                Op::EndIf => {
+                    branches.pop().ok_or(ScriptFailed)?;
+                    continue;
+                }
+                _ if !executing => continue,
+                _ => {}
+            }
+
+            match *op {
+                Op::Push(ref data) => {
+                    if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                        return Err(ScriptLimitExceeded);
+                    }
+                    stack.push(data.clone());
+                }
+                Op::Dup => {
+                    let top = stack.last().ok_or(ScriptFailed)?.clone();
+                    stack.push(top);
+                }
+                Op::Drop => {
+                    stack.pop().ok_or(ScriptFailed)?;
+                }
+                Op::Swap => {
+                    let len = stack.len();
+                    if len < 2 {
+                        return Err(ScriptFailed);
+                    }
+                    stack.swap(len - 1, len - 2);
+                }
+                Op::Sha256 => {
+                    let data = stack.pop().ok_or(ScriptFailed)?;
+                    let mut hash = [0; 32];
+                    let mut hasher = Sha256::new();
+                    hasher.input(&data);
+                    hasher.result(&mut hash);
+                    stack.push(hash.to_vec());
+                }
+                Op::Hash160 => {
+                    let data = stack.pop().ok_or(ScriptFailed)?;
+                    stack.push(hash160(&data).to_vec());
+                }
+                Op::Equal | Op::EqualVerify => {
+                    let a = stack.pop().ok_or(ScriptFailed)?;
+                    let b = stack.pop().ok_or(ScriptFailed)?;
+                    stack.push(encode_bool(a == b));
+                    if *op == Op::EqualVerify {
+                        verify(stack)?;
+                    }
+                }
+                Op::Verify => verify(stack)?,
+                Op::Return => return Err(ScriptFailed),
+                Op::CheckSig | Op::CheckSigVerify => {
+                    add_sigops(sigops, 1)?;
+                    let public_key = stack.pop().ok_or(ScriptFailed)?;
+                    let signature = stack.pop().ok_or(ScriptFailed)?;
+                    stack.push(encode_bool(checker.check_signature(&signature, &public_key)));
+                    if *op == Op::CheckSigVerify {
+                        verify(stack)?;
+                    }
+                }
+                Op::CheckMultiSig => {
+                    let total = pop_count(stack, MAX_MULTISIG_KEYS)?;
+                    add_sigops(sigops, total)?;
+                    let public_keys = pop_items(stack, total)?;
+                    let required = pop_count(stack, total)?;
+                    let signatures = pop_items(stack, required)?;
+
+                    // Each signature must match a later key than the last
+                    let mut keys = public_keys.iter();
+                    let valid = signatures.iter().all(|signature| {
+                        keys.any(|public_key| checker.check_signature(signature, public_key))
+                    });
+                    stack.push(encode_bool(valid));
+                }
+                Op::Not => {
+                    let n = decode_num(&stack.pop().ok_or(ScriptFailed)?)?;
+                    stack.push(encode_bool(n == 0));
+                }
+                Op::Add | Op::Sub | Op::NumEqual | Op::LessThan | Op::GreaterThan => {
+                    let b = decode_num(&stack.pop().ok_or(ScriptFailed)?)?;
+                    let a = decode_num(&stack.pop().ok_or(ScriptFailed)?)?;
+                    let result = match *op {
+                        Op::Add => a.checked_add(b).ok_or(ScriptFailed)?,
+                        Op::Sub => a.checked_sub(b).ok_or(ScriptFailed)?,
+                        Op::NumEqual => (a == b) as i64,
+                        Op::LessThan => (a < b) as i64,
+                        _ => (a > b) as i64,
+                    };
+                    stack.push(encode_num(result));
+                }
+                Op::CheckLockTimeVerify | Op::CheckSequenceVerify => {
+                    let n = decode_num(stack.last().ok_or(ScriptFailed)?)?;
+                    if n < 0 {
+                        return Err(ScriptFailed);
+                    }
+                    let unlocked = if *op == Op::CheckLockTimeVerify {
+                        checker.check_lock_time(n as u64)
+                    } else {
+                        checker.check_relative_lock_time(n as u64)
+                    };
+                    if !unlocked {
+                        return Err(ScriptFailed);
+                    }
+                }
+                Op::If | Op::NotIf | Op::Else | Op::EndIf => unreachable!(),
+            }
+
+            if stack.len() > MAX_STACK_SIZE {
+                return Err(ScriptLimitExceeded);
+            }
+        }
+
+        if !branches.is_empty() {
+            return Err(ScriptFailed);
+        }
+        Ok(())
+    }
+}
+
+// Counts `n` more signature checks, failing past the limit
+fn add_sigops(sigops: &mut usize, n: usize) -> Result<()> {
+    *sigops += n;
+    if *sigops > MAX_SCRIPT_SIGOPS {
+        return Err(Error::ScriptLimitExceeded);
+    }
+    Ok(())
+}
+
+// Pops the top of the stack, failing unless it is true
+fn verify(stack: &mut Vec<Vec<u8>>) -> Result<()> {
+    if is_true(&stack.pop().ok_or(Error::ScriptFailed)?) {
+        Ok(())
+    } else {
+        Err(Error::ScriptFailed)
+    }
+}
+
+/// Runs the unlocking script, which may only push data, and then the
+/// locking script on the resulting stack. The output may be spent if both
+/// succeed and leave true on top of the stack.
+pub fn verify_scripts(unlock: &Script, lock: &Script, checker: &dyn Checker) -> Result<()> {
+    if !unlock.is_push_only() {
+        return Err(Error::ScriptFailed);
+    }
+
+    let mut stack = Vec::new();
+    let mut sigops = 0;
+    unlock.run(&mut stack, checker, &mut sigops)?;
+    lock.run(&mut stack, checker, &mut sigops)?;
+
+    match stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(Error::ScriptFailed),
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::Push(ref data) if data.is_empty() => write!(f, "0"),
+            Op::Push(ref data) => write!(f, "0x{}", data.to_hex()),
+            _ => {
+                let &(name, _) = NAMES.iter().find(|&(_, op)| op == self).unwrap();
+                write!(f, "{}", name)
+            }
+        }
+    }
+}
+
+impl FromStr for Op {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Op> {
+        if let Some(hex) = s.strip_prefix("0x") {
+            return hex.from_hex().map(Op::Push).map_err(|_| Error::InvalidScript);
+        }
+        if let Ok(n) = s.parse::<i64>() {
+            return Ok(Op::Push(encode_num(n)));
+        }
+        NAMES
+            .iter()
+            .find(|&&(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, op)| op.clone())
+            .ok_or(Error::InvalidScript)
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ops: Vec<String> = self.ops.iter().map(|op| op.to_string()).collect();
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+impl FromStr for Script {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Script> {
+        let ops = s.split_whitespace()
+            .map(|op| op.parse())
+            .collect::<Result<Vec<Op>>>()?;
+        Ok(Script::new(ops))
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Script, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|err: Error| de::Error::custom(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Accepts a signature equal to the public key, and lock times up to
+    // its own
+    struct MockChecker {
+        lock_time: u64,
+        relative_lock_time: u64,
+    }
+
+    impl Checker for MockChecker {
+        fn check_signature(&self, signature: &[u8], public_key: &[u8]) -> bool {
+            !signature.is_empty() && signature == public_key
+        }
+
+        fn check_lock_time(&self, lock_time: u64) -> bool {
+            lock_time <= self.lock_time
+        }
+
+        fn check_relative_lock_time(&self, relative_lock_time: u64) -> bool {
+            relative_lock_time <= self.relative_lock_time
+        }
+    }
+
+    const CHECKER: MockChecker = MockChecker {
+        lock_time: 100,
+        relative_lock_time: 10,
+    };
+
+    // Runs a written script on an empty stack, returning the stack
+    fn run(script: &str) -> Result<Vec<Vec<u8>>> {
+        let mut stack = Vec::new();
+        script.parse::<Script>()?.run(&mut stack, &CHECKER, &mut 0)?;
+        Ok(stack)
+    }
+
+    fn nums(numbers: &[i64]) -> Vec<Vec<u8>> {
+        numbers.iter().map(|&n| encode_num(n)).collect()
+    }
+
+    fn assert_fails(script: &str) {
+        match run(script) {
+            Err(Error::ScriptFailed) => {}
+            other => panic!("expected `{}` to fail, got {:?}", script, other),
+        }
+    }
+
+    fn assert_exceeds_limits(result: Result<Vec<Vec<u8>>>) {
+        match result {
+            Err(Error::ScriptLimitExceeded) => {}
+            other => panic!("expected ScriptLimitExceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        for &n in &[0, 1, -1, 127, 128, -128, 255, 256, 1 << 40, i64::MAX, i64::MIN] {
+            assert_eq!(decode_num(&encode_num(n)).unwrap(), n);
+        }
+        assert_eq!(encode_num(0), Vec::<u8>::new());
+        assert_eq!(encode_num(128), vec![0x80, 0x00]);
+        assert_eq!(encode_num(-1), vec![0xff]);
+        assert!(decode_num(&[0; MAX_SCRIPT_NUM_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn scripts_round_trip_through_their_written_form() {
+        let written = "0 0x0102 DUP HASH160 EQUALVERIFY CHECKSIG";
+        let script: Script = written.parse().unwrap();
+        assert_eq!(script.to_string(), written);
+        assert_eq!(script.ops[0], Op::Push(vec![]));
+        assert!("FOO".parse::<Script>().is_err());
+    }
+
+    #[test]
+    fn stack_operations() {
+        assert_eq!(run("1 2 DUP").unwrap(), nums(&[1, 2, 2]));
+        assert_eq!(run("1 2 DROP").unwrap(), nums(&[1]));
+        assert_eq!(run("1 2 SWAP").unwrap(), nums(&[2, 1]));
+        assert_fails("DUP");
+        assert_fails("DROP");
+        assert_fails("1 SWAP");
+    }
+
+    #[test]
+    fn hash_operations() {
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(run("0 SHA256").unwrap(), vec![sha256.from_hex().unwrap()]);
+        let hash160 = "b472a266d0bd89c13706a4132ccfb16f7c3b9fcb";
+        assert_eq!(run("0 HASH160").unwrap(), vec![hash160.from_hex().unwrap()]);
+        assert_fails("SHA256");
+        assert_fails("HASH160");
+    }
+
+    #[test]
+    fn comparison_and_verify_operations() {
+        assert_eq!(run("0x01 0x01 EQUAL").unwrap(), nums(&[1]));
+        assert_eq!(run("0x01 0x02 EQUAL").unwrap(), nums(&[0]));
+        assert_eq!(run("7 0x07 EQUALVERIFY").unwrap(), nums(&[]));
+        assert_fails("1 2 EQUALVERIFY");
+        assert_eq!(run("1 VERIFY").unwrap(), nums(&[]));
+        assert_fails("0 VERIFY");
+        assert_fails("1 RETURN");
+    }
+
+    #[test]
+    fn arithmetic_operations() {
+        assert_eq!(run("2 3 ADD").unwrap(), nums(&[5]));
+        assert_eq!(run("2 3 SUB").unwrap(), nums(&[-1]));
+        assert_eq!(run("0 NOT 5 NOT").unwrap(), nums(&[1, 0]));
+        assert_eq!(run("4 4 NUMEQUAL 4 5 NUMEQUAL").unwrap(), nums(&[1, 0]));
+        assert_eq!(run("4 5 LESSTHAN 5 4 LESSTHAN").unwrap(), nums(&[1, 0]));
+        assert_eq!(run("5 4 GREATERTHAN 4 5 GREATERTHAN").unwrap(), nums(&[1, 0]));
+        assert_fails(&format!("{} 1 ADD", i64::MAX));
+        assert_fails(&format!("{} 1 SUB", i64::MIN));
+        assert_fails("1 ADD");
+    }
+
+    #[test]
+    fn signature_operations() {
+        assert_eq!(run("0x0a 0x0a CHECKSIG").unwrap(), nums(&[1]));
+        assert_eq!(run("0x0a 0x0b CHECKSIG").unwrap(), nums(&[0]));
+        assert_eq!(run("0x0a 0x0a CHECKSIGVERIFY").unwrap(), nums(&[]));
+        assert_fails("0x0a 0x0b CHECKSIGVERIFY");
+        assert_fails("0x0a CHECKSIG");
+    }
+
+    #[test]
+    fn lock_time_operations_leave_the_number_in_place() {
+        assert_eq!(run("100 CHECKLOCKTIMEVERIFY").unwrap(), nums(&[100]));
+        assert_fails("101 CHECKLOCKTIMEVERIFY");
+        assert_eq!(run("10 CHECKSEQUENCEVERIFY").unwrap(), nums(&[10]));
+        assert_fails("11 CHECKSEQUENCEVERIFY");
+        assert_fails("-1 CHECKLOCKTIMEVERIFY");
+        assert_fails("CHECKSEQUENCEVERIFY");
+    }
+
+    #[test]
+    fn branches_run_one_side() {
+        assert_eq!(run("1 IF 2 ELSE 3 ENDIF").unwrap(), nums(&[2]));
+        assert_eq!(run("0 IF 2 ELSE 3 ENDIF").unwrap(), nums(&[3]));
+        assert_eq!(run("0 NOTIF 2 ELSE 3 ENDIF").unwrap(), nums(&[2]));
+        assert_eq!(run("0 IF RETURN ENDIF 4").unwrap(), nums(&[4]));
+    }
+
+    #[test]
+    fn nested_branches_follow_every_enclosing_condition() {
+        let script = "IF IF 1 ELSE 2 ENDIF ELSE IF 3 ELSE 4 ENDIF ENDIF";
+        assert_eq!(run(&format!("1 1 {}", script)).unwrap(), nums(&[1]));
+        assert_eq!(run(&format!("0 1 {}", script)).unwrap(), nums(&[2]));
+        assert_eq!(run(&format!("1 0 {}", script)).unwrap(), nums(&[3]));
+        assert_eq!(run(&format!("0 0 {}", script)).unwrap(), nums(&[4]));
+        // The inner condition is not popped inside a skipped branch
+        assert_eq!(run("5 0 IF IF 1 ENDIF ENDIF").unwrap(), nums(&[5]));
+    }
+
+    #[test]
+    fn unbalanced_branches_fail() {
+        assert_fails("1 IF 2");
+        assert_fails("1 IF 2 ELSE 3");
+        assert_fails("ELSE");
+        assert_fails("ENDIF");
+        assert_fails("1 IF 2 ENDIF ENDIF");
+        assert_fails("IF 1 ENDIF");
+    }
+
+    #[test]
+    fn multisig_checks_signatures_in_key_order() {
+        let script = "2 0x0a 0x0b 0x0c 3 CHECKMULTISIG";
+        assert_eq!(run(&format!("0x0a 0x0c {}", script)).unwrap(), nums(&[1]));
+        assert_eq!(run(&format!("0x0b 0x0c {}", script)).unwrap(), nums(&[1]));
+        assert_eq!(run(&format!("0x0c 0x0a {}", script)).unwrap(), nums(&[0]));
+        assert_eq!(run(&format!("0x0a 0x0a {}", script)).unwrap(), nums(&[0]));
+        assert_eq!(run(&format!("0x0a 0x0d {}", script)).unwrap(), nums(&[0]));
+    }
+
+    #[test]
+    fn multisig_scripts_parse_back() {
+        let keys = vec![vec![0x0a], vec![0x0b], vec![0x0c]];
+        let script = Script::multisig(2, &keys);
+        assert_eq!(script.parse_multisig(), Some((2, keys.clone())));
+
+        // The counts must match the keys, and every key must be a push
+        let mut wrong_total = script.clone();
+        wrong_total.ops[4] = Op::Push(encode_num(2));
+        assert_eq!(wrong_total.parse_multisig(), None);
+        assert_eq!(Script::multisig(4, &keys).parse_multisig(), None);
+        let mut not_a_key = script.clone();
+        not_a_key.ops[2] = Op::Dup;
+        assert_eq!(not_a_key.parse_multisig(), None);
+        let mut not_multisig = script;
+        not_multisig.ops[5] = Op::CheckSig;
+        assert_eq!(not_multisig.parse_multisig(), None);
+    }
+
+    #[test]
+    fn multisig_fails_on_malformed_counts() {
+        // Too few signatures on the stack
+        assert_fails("0x0a 2 0x0a 0x0b 2 CHECKMULTISIG");
+        // More signatures required than keys
+        assert_fails("0x0a 0x0b 0x0c 3 0x0a 0x0b 2 CHECKMULTISIG");
+        // More keys than allowed
+        assert_fails(&format!("1 {} CHECKMULTISIG", MAX_MULTISIG_KEYS + 1));
+        assert_fails("0x0a 1 0x0a -1 CHECKMULTISIG");
+    }
+
+    #[test]
+    fn scripts_are_limited_in_size_and_operations() {
+        let drops = |n| format!("{} 1", vec!["1 DROP"; n].join(" "));
+        assert!(run(&drops(MAX_SCRIPT_OPS)).is_ok());
+        assert_exceeds_limits(run(&drops(MAX_SCRIPT_OPS + 1)));
+
+        let element = format!("0x{}", "00".repeat(MAX_SCRIPT_ELEMENT_SIZE + 1));
+        assert_exceeds_limits(run(&element));
+
+        let script = Script::new(vec![Op::Push(vec![0; 100]); MAX_SCRIPT_SIZE / 100]);
+        assert_exceeds_limits(script.run(&mut Vec::new(), &CHECKER, &mut 0).map(|_| vec![]));
+    }
+
+    #[test]
+    fn the_stack_is_limited_in_size() {
+        let pushes = vec!["1"; MAX_STACK_SIZE].join(" ");
+        assert_eq!(run(&pushes).unwrap().len(), MAX_STACK_SIZE);
+        assert_exceeds_limits(run(&format!("{} DUP", pushes)));
+    }
+
+    #[test]
+    fn signature_checks_are_limited_across_both_scripts() {
+        let keys = vec!["0x0a"; MAX_MULTISIG_KEYS].join(" ");
+        let lock = format!("0 {} {} CHECKMULTISIG", keys, MAX_MULTISIG_KEYS);
+        let lock: Script = lock.parse().unwrap();
+        assert!(verify_scripts(&Script::default(), &lock, &CHECKER).is_ok());
+
+        let lock: Script = format!("0x0a 0x0a CHECKSIGVERIFY {}", lock).parse().unwrap();
+        match verify_scripts(&Script::default(), &lock, &CHECKER) {
+            Err(Error::ScriptLimitExceeded) => {}
+            other => panic!("expected ScriptLimitExceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unlocking_scripts_only_push_data() {
+        let lock: Script = "0x0a EQUAL".parse().unwrap();
+        assert!(verify_scripts(&"0x0a".parse().unwrap(), &lock, &CHECKER).is_ok());
+        assert!(verify_scripts(&"0x0b".parse().unwrap(), &lock, &CHECKER).is_err());
+        assert!(verify_scripts(&"0x0a DUP DROP".parse().unwrap(), &lock, &CHECKER).is_err());
+        // The script must leave true on top of the stack
+        assert!(verify_scripts(&"1".parse().unwrap(), &"0".parse().unwrap(), &CHECKER).is_err());
+        assert!(verify_scripts(&Script::default(), &Script::default(), &CHECKER).is_err());
+    }
+}
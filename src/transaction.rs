@@ -1,29 +1,74 @@
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use rustc_serialize::hex::{FromHex, ToHex};
 use serialize::serialize;
-use utxo;
-use blockchain::Blockchain;
+use utxo::UTXO;
 use secp256k1::key::{PublicKey, SecretKey};
 use secp256k1::{Secp256k1, Message, Signature};
-use wallet::{hash_public_key, Wallet};
+use wallet::{hash_public_key, HashType, Wallet};
+use address::Address;
 use rand::OsRng;
 use rand::Rng;
 use error::{Error, Result};
+use coinselect::{self, Coin, CoinSelector};
+use blockchain::Blockchain;
+use constants::{
+    COINBASE_MATURITY, LOCKTIME_THRESHOLD, MAX_DATA_SIZE, MAX_MEMO_SIZE, MAX_SCRIPT_SIZE,
+    RELATIVE_LOCK_SECONDS, SCHNORR_OUTPUT_VERSION,
+};
+use script::{self, Checker, Op, Script};
+use schnorr::{self, SchnorrSignature};
+use htlc::Htlc;
+use verify;
 
-const REWARD: u64 = 50;
+/// The coins created by each block, which its miner is paid on top of the
+/// fees of its transactions.
+pub const REWARD: u64 = 50;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TXInput {
     pub txid: String,
     pub vout: usize,
-    pub signature: Signature,
-    pub pubkey: PublicKey,
+    /// The signature and public key spending an output locked to a public
+    /// key hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Signature>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<PublicKey>,
+    /// The signature spending an output of the Schnorr version, in place
+    /// of `signature`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schnorr_signature: Option<SchnorrSignature>,
+    /// The script spending an output locked by a script or script hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unlock: Option<Script>,
+    /// The script whose hash an output is locked to, run after `unlock`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redeem_script: Option<Script>,
+    /// The number of blocks after the spent output confirmed before the
+    /// input can be mined, or seconds if `RELATIVE_LOCK_SECONDS` is set.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub relative_lock_time: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TXOutput {
     pub value: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub pubkey_hash: String,
+    #[serde(default, skip_serializing_if = "HashType::is_sha256")]
+    pub hash_type: HashType,
+    /// Locks the output by a script instead of a public key hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<Script>,
+    /// Locks the output to the hex hash of a script, which the spending
+    /// input reveals.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub script_hash: String,
+    /// How inputs spending an output locked to a public key hash are
+    /// signed: 0 for ECDSA, or `SCHNORR_OUTPUT_VERSION`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub version: u8,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -36,6 +81,29 @@ pub struct CoinbaseTX {
 pub struct StandardTX {
     pub outputs: Vec<TXOutput>,
     pub inputs: Vec<TXInput>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub replaceable: bool,
+    /// The height, or the time if at least `LOCKTIME_THRESHOLD`, before
+    /// which the transaction cannot be mined.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub lock_time: u64,
+    /// A reference for the payment, such as an invoice number.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub memo: String,
+}
+
+/// Optional settings for a new `StandardTX`.
+#[derive(Default)]
+pub struct TXOptions {
+    /// The amount paid to the miner, on top of the amount sent.
+    pub fee: u64,
+    /// Whether the transaction may be replaced in the mempool by one paying
+    /// a higher fee.
+    pub replaceable: bool,
+    /// The height or time before which the transaction cannot be mined.
+    pub lock_time: u64,
+    /// A reference for the payment, at most `MAX_MEMO_SIZE` bytes.
+    pub memo: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -44,16 +112,59 @@ pub enum TX {
     Standard(StandardTX),
 }
 
+/// A collection of transactions whose outputs new inputs may spend.
+pub trait TXSource {
+    fn find_transaction(&self, txid: &str) -> Option<&TX>;
+}
+
 #[derive(Serialize, Deserialize)]
 struct TransactionData {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pubkey_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    script: Option<Script>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    script_hash: String,
     outputs: Vec<TXOutput>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    replaceable: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    relative_lock_time: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    lock_time: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    memo: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    version: u8,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+    *n == T::default()
 }
 
 impl TransactionData {
+    // The data signed by `input` of `tx` to spend `prev_output`
+    fn new(tx: &StandardTX, input: &TXInput, prev_output: &TXOutput) -> TransactionData {
+        TransactionData {
+            pubkey_hash: prev_output.pubkey_hash.clone(),
+            script: prev_output.script.clone(),
+            script_hash: prev_output.script_hash.clone(),
+            outputs: tx.outputs.clone(),
+            replaceable: tx.replaceable,
+            relative_lock_time: input.relative_lock_time,
+            lock_time: tx.lock_time,
+            memo: tx.memo.clone(),
+            version: prev_output.version,
+        }
+    }
+
     fn id(&self) -> Vec<u8> {
         let mut hash = Sha256::new();
-        hash.input_str(&serialize(&self));
+        hash.input_str(&serialize(self));
         let mut res = vec![0; 32];
         hash.result(&mut res);
 
@@ -64,30 +175,205 @@ impl TransactionData {
         let secp = Secp256k1::new();
         let data = self.id();
         let msg = Message::from_slice(&data).unwrap();
-        let signature = secp.sign(&msg, sk).unwrap();
-
-        signature
+        secp.sign(&msg, sk).unwrap()
     }
 
     fn verify(&self, sig: &Signature, pk: &PublicKey) -> bool {
         let data = self.id();
         let msg = Message::from_slice(&data).unwrap();
-        let secp = Secp256k1::new();
 
-        match secp.verify(&msg, sig, pk) {
-            Ok(_) => true,
-            Err(_) => false,
+        verify::context().verify(&msg, sig, pk).is_ok()
+    }
+}
+
+// Checks the signatures in a script against the data signed for an input
+struct InputChecker {
+    data: TransactionData,
+}
+
+impl Checker for InputChecker {
+    fn check_signature(&self, signature: &[u8], public_key: &[u8]) -> bool {
+        let secp = verify::context();
+        match (Signature::from_der(secp, signature), PublicKey::from_slice(secp, public_key)) {
+            (Ok(signature), Ok(public_key)) => self.data.verify(&signature, &public_key),
+            _ => false,
+        }
+    }
+
+    fn check_lock_time(&self, lock_time: u64) -> bool {
+        let is_height = |lock_time| lock_time < LOCKTIME_THRESHOLD;
+        is_height(lock_time) == is_height(self.data.lock_time) && lock_time <= self.data.lock_time
+    }
+
+    fn check_relative_lock_time(&self, relative_lock_time: u64) -> bool {
+        let is_seconds = |relative_lock_time| relative_lock_time & RELATIVE_LOCK_SECONDS != 0;
+        is_seconds(relative_lock_time) == is_seconds(self.data.relative_lock_time)
+            && relative_lock_time <= self.data.relative_lock_time
+    }
+}
+
+impl TXInput {
+    /// An input spending `coin`, before it is unlocked.
+    pub fn unsigned(coin: &Coin) -> TXInput {
+        TXInput {
+            txid: coin.txid.clone(),
+            vout: coin.vout,
+            signature: None,
+            pubkey: None,
+            schnorr_signature: None,
+            unlock: None,
+            redeem_script: None,
+            relative_lock_time: 0,
         }
     }
 }
 
-impl CoinbaseTX {
-    pub fn new(to: String) -> CoinbaseTX {
-        let out = TXOutput {
-            value: REWARD,
-            pubkey_hash: to,
+impl TXOutput {
+    pub fn new(value: u64, to: &Address) -> TXOutput {
+        match to.script_hash() {
+            Some(script_hash) => TXOutput {
+                value,
+                pubkey_hash: String::new(),
+                hash_type: HashType::default(),
+                script: None,
+                script_hash,
+                version: 0,
+            },
+            None => TXOutput {
+                value,
+                pubkey_hash: to.pubkey_hash(),
+                hash_type: to.hash_type(),
+                script: None,
+                script_hash: String::new(),
+                version: if to.is_schnorr() { SCHNORR_OUTPUT_VERSION } else { 0 },
+            },
+        }
+    }
+
+    /// An output that can be spent by anyone whose unlocking script
+    /// satisfies `script`.
+    pub fn with_script(value: u64, script: Script) -> TXOutput {
+        TXOutput {
+            value,
+            pubkey_hash: String::new(),
+            hash_type: HashType::default(),
+            script: Some(script),
+            script_hash: String::new(),
+            version: 0,
+        }
+    }
+
+    /// A provably unspendable output carrying `payload`, such as the hash
+    /// of a document to anchor in the blockchain.
+    pub fn data(payload: Vec<u8>) -> Result<TXOutput> {
+        if payload.len() > MAX_DATA_SIZE {
+            return Err(Error::DataTooLarge);
+        }
+        Ok(TXOutput::with_script(0, Script::new(vec![Op::Return, Op::Push(payload)])))
+    }
+
+    /// The payload of a data output, or `None` if it is not one.
+    pub fn payload(&self) -> Option<&[u8]> {
+        match self.script {
+            Some(ref script) => match *script.ops.as_slice() {
+                [Op::Return, Op::Push(ref payload)] => Some(payload),
+                _ => None,
+            },
+            None => None,
+        }
+    }
+
+    /// Whether no unlocking script can ever spend the output, as its
+    /// locking script starts by failing.
+    pub fn is_unspendable(&self) -> bool {
+        self.script.as_ref().is_some_and(|script| script.ops.first() == Some(&Op::Return))
+    }
+
+    /// An output paying `to`, a public key hash address, that cannot be
+    /// spent before the height or time `lock_time`, which a script number
+    /// must hold.
+    pub fn time_locked(value: u64, to: &Address, lock_time: u64) -> Result<TXOutput> {
+        if to.is_script() {
+            return Err(Error::InvalidAddress);
+        }
+        if lock_time > i64::MAX as u64 {
+            return Err(Error::LockTimeOutOfRange);
+        }
+        let hash = match to.hash_type() {
+            HashType::Sha256 => Op::Sha256,
+            HashType::Hash160 => Op::Hash160,
         };
-        let outputs = vec![out];
+        let script = Script::new(vec![
+            Op::Push(script::encode_num(lock_time as i64)),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::Dup,
+            hash,
+            Op::Push(to.pubkey_hash().from_hex().unwrap()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ]);
+
+        Ok(TXOutput::with_script(value, script))
+    }
+
+    /// The address and lock time of an output made by `time_locked`, or
+    /// `None` if it is not one.
+    pub fn time_lock(&self) -> Option<(Address, u64)> {
+        let script = self.script.as_ref()?;
+        match *script.ops.as_slice() {
+            [Op::Push(ref lock_time), Op::CheckLockTimeVerify, Op::Drop, Op::Dup, ref hash,
+             Op::Push(ref pubkey_hash), Op::EqualVerify, Op::CheckSig] => {
+                let hash_type = match *hash {
+                    Op::Sha256 => HashType::Sha256,
+                    Op::Hash160 => HashType::Hash160,
+                    _ => return None,
+                };
+                let lock_time = script::decode_num(lock_time).ok().filter(|&n| n >= 0)?;
+                let to = Address::from_pubkey_hash(&pubkey_hash.to_hex(), hash_type).ok()?;
+                Some((to, lock_time as u64))
+            }
+            _ => None,
+        }
+    }
+
+    /// The address whose wallet the output belongs to: the address it pays,
+    /// the payee of a time-locked output, or the recipient of an HTLC, which
+    /// is the sender's again only if it times out unclaimed.
+    pub fn payee(&self) -> Option<Address> {
+        if let Some((to, _)) = self.time_lock() {
+            return Some(to);
+        }
+        match self.script.as_ref().and_then(Htlc::parse) {
+            Some(htlc) => Some(htlc.recipient),
+            None => self.address().ok(),
+        }
+    }
+
+    /// The address the output pays.
+    pub fn address(&self) -> Result<Address> {
+        if !self.script_hash.is_empty() {
+            return Address::from_script_hash(&self.script_hash);
+        }
+        let address = Address::from_pubkey_hash(&self.pubkey_hash, self.hash_type)?;
+        if self.is_schnorr() {
+            return address.to_schnorr();
+        }
+        Ok(address)
+    }
+
+    /// Whether inputs spending the output are signed with Schnorr
+    /// signatures.
+    pub fn is_schnorr(&self) -> bool {
+        self.version == SCHNORR_OUTPUT_VERSION
+    }
+}
+
+impl CoinbaseTX {
+    /// Pays the block reward and `fees`, those of the other transactions in
+    /// the block, to `to`.
+    pub fn new(to: &Address, fees: u64) -> CoinbaseTX {
+        let outputs = vec![TXOutput::new(REWARD + fees, to)];
 
         let rand = OsRng::new().unwrap().next_u64();
 
@@ -96,81 +382,254 @@ impl CoinbaseTX {
 }
 
 impl StandardTX {
-    pub fn new(bc: &Blockchain, wallet: &Wallet, to: &str, amount: u64) -> Result<StandardTX> {
-        let utxo = utxo::find(bc);
+    fn sign_inputs(
+        coins: Vec<Coin>,
+        outputs: Vec<TXOutput>,
+        options: &TXOptions,
+        wallet: &Wallet,
+    ) -> Result<StandardTX> {
+        let secret_key = wallet.secret_key()?;
+        let mut tx = StandardTX {
+            inputs: coins.iter().map(TXInput::unsigned).collect(),
+            outputs,
+            replaceable: options.replaceable,
+            lock_time: options.lock_time,
+            memo: options.memo.clone(),
+        };
 
-        // Find the outputs needed for the new transaction inputs
-        let mut acc_amount = 0;
-        let old_outputs: Vec<(String, usize, TXOutput)> = utxo.iter()
+        for (index, coin) in coins.iter().enumerate() {
+            let data = TransactionData::new(&tx, &tx.inputs[index], &coin.output);
+            let input = &mut tx.inputs[index];
+            if coin.output.is_schnorr() {
+                input.schnorr_signature = Some(schnorr::sign(&data.id(), secret_key));
+            } else {
+                input.signature = Some(data.sign(secret_key));
+            }
+            input.pubkey = Some(wallet.public_key);
+        }
+
+        Ok(tx)
+    }
+
+    /// Selects coins of `from` to pay `payments` and the fee, returning them
+    /// with the new outputs: the payments, then any change back to `from`.
+    pub fn select_coins(
+        utxo: &UTXO,
+        from: &Address,
+        payments: &[TXOutput],
+        selector: &dyn CoinSelector,
+        options: &TXOptions,
+    ) -> Result<(Vec<Coin>, Vec<TXOutput>)> {
+        // Collect the unspent outputs of the address, in a stable order
+        let mut coins: Vec<Coin> = utxo.iter()
             .flat_map(|(txid, entries)| {
-                entries.iter().map(move |entry| (txid, entry))
-            })
-            .filter(|&(_, entry)| {
-                entry.output.pubkey_hash == hash_public_key(&wallet.public_key)
-            })
-            .take_while(move |_| acc_amount <= amount)
-            .map(|(txid, entry)| {
-                acc_amount += entry.output.value;
-                (txid.to_owned(), entry.vout, entry.output.clone())
+                entries.iter().map(move |entry| {
+                    Coin {
+                        txid: txid.clone(),
+                        vout: entry.vout,
+                        output: entry.output.clone(),
+                    }
+                })
             })
+            .filter(|coin| coin.output.address().ok().as_ref() == Some(from))
             .collect();
+        coins.sort_by(|a, b| (&a.txid, a.vout).cmp(&(&b.txid, b.vout)));
 
-        if acc_amount < amount {
+        // Find the outputs needed for the new transaction inputs
+        let amount: u64 = payments.iter().map(|output| output.value).sum();
+        let target = amount.checked_add(options.fee).ok_or(Error::InsufficientFunds)?;
+        if coinselect::total(&coins) < target {
             return Err(Error::InsufficientFunds);
         }
+        let old_outputs = selector
+            .select(&coins, target)
+            .ok_or(Error::CoinSelectionFailed)?;
+        let acc_amount = coinselect::total(&old_outputs);
 
         // Make the new transaction outputs
-        let send = TXOutput {
-            value: amount,
-            pubkey_hash: to.to_owned(),
+        let mut new_outputs = payments.to_vec();
+        if acc_amount > target {
+            new_outputs.push(TXOutput::new(acc_amount - target, from));
+        }
+
+        Ok((old_outputs, new_outputs))
+    }
+
+    /// Pays `payments` from the coins of `from`, one of the addresses of
+    /// `wallet`, sending any change back to it.
+    pub fn new(
+        utxo: &UTXO,
+        wallet: &Wallet,
+        from: &Address,
+        payments: &[TXOutput],
+        selector: &dyn CoinSelector,
+        options: &TXOptions,
+    ) -> Result<StandardTX> {
+        if from.pubkey_hash() != wallet.pubkey_hash() {
+            return Err(Error::NoWalletForAddress);
+        }
+        let (coins, outputs) = StandardTX::select_coins(utxo, from, payments, selector, options)?;
+
+        StandardTX::sign_inputs(coins, outputs, options, wallet)
+    }
+
+    /// Spends `coin`, an output locked by a script, to `outputs`, after
+    /// `relative_lock_time`. `unlock` is given the digest that signatures in
+    /// the unlocking script must sign, and returns the unlocking script.
+    pub fn spend_script<F>(
+        coin: &Coin,
+        outputs: Vec<TXOutput>,
+        options: &TXOptions,
+        relative_lock_time: u64,
+        unlock: F,
+    ) -> Result<StandardTX>
+    where
+        F: FnOnce(&[u8]) -> Result<Script>,
+    {
+        let mut input = TXInput::unsigned(coin);
+        input.relative_lock_time = relative_lock_time;
+        let mut tx = StandardTX {
+            inputs: vec![input],
+            outputs,
+            replaceable: options.replaceable,
+            lock_time: options.lock_time,
+            memo: options.memo.clone(),
         };
-        let change = TXOutput {
-            value: acc_amount - amount,
-            pubkey_hash: hash_public_key(&wallet.public_key),
+        tx.inputs[0].unlock = Some(unlock(&tx.signature_hash(0, &coin.output))?);
+
+        Ok(tx)
+    }
+
+    /// The digest that signatures of input `index`, spending `prev_output`,
+    /// sign.
+    pub fn signature_hash(&self, index: usize, prev_output: &TXOutput) -> Vec<u8> {
+        TransactionData::new(self, &self.inputs[index], prev_output).id()
+    }
+
+    /// Makes a replacement for this transaction that pays `fee` in total,
+    /// taking the extra fee out of the wallet's change output.
+    pub fn bump_fee<S: TXSource + ?Sized>(
+        &self,
+        source: &S,
+        wallet: &Wallet,
+        fee: u64,
+    ) -> Result<StandardTX> {
+        let pubkey_hash = wallet.pubkey_hash();
+        let old_fee = self.fee(source)?;
+        if fee <= old_fee {
+            return Err(Error::InsufficientReplacementFee);
+        }
+        let extra = fee - old_fee;
+
+        let mut coins = Vec::new();
+        for input in self.inputs.iter() {
+            if input.pubkey != Some(wallet.public_key) {
+                return Err(Error::NoWalletForAddress);
+            }
+            coins.push(Coin {
+                txid: input.txid.clone(),
+                vout: input.vout,
+                output: prev_output(source, input)?.clone(),
+            });
+        }
+
+        // The change is the last output paying back to the wallet
+        let mut outputs = self.outputs.clone();
+        let change = outputs
+            .iter()
+            .rposition(|output| output.pubkey_hash == pubkey_hash)
+            .ok_or(Error::InsufficientFunds)?;
+        if outputs[change].value < extra {
+            return Err(Error::InsufficientFunds);
+        }
+        outputs[change].value -= extra;
+        if outputs[change].value == 0 {
+            outputs.remove(change);
+        }
+
+        let options = TXOptions {
+            fee,
+            replaceable: self.replaceable,
+            lock_time: self.lock_time,
+            memo: self.memo.clone(),
         };
-        let new_outputs = vec![send, change];
-
-        // Make the new tranaction inputs
-        let new_inputs = old_outputs
-            .into_iter()
-            .map(|(txid, vout, output)| {
-                let data = TransactionData {
-                    pubkey_hash: output.pubkey_hash,
-                    outputs: new_outputs.clone(),
-                };
+        StandardTX::sign_inputs(coins, outputs, &options, wallet)
+    }
 
-                TXInput {
-                    txid: txid,
-                    vout,
-                    signature: data.sign(&wallet.secret_key),
-                    pubkey: wallet.public_key.clone(),
-                }
+    /// The amount paid to the miner: the value of the spent outputs minus
+    /// the value of the new outputs.
+    pub fn fee<S: TXSource + ?Sized>(&self, source: &S) -> Result<u64> {
+        let mut input_value: u64 = 0;
+        for input in self.inputs.iter() {
+            input_value = input_value
+                .checked_add(prev_output(source, input)?.value)
+                .ok_or(Error::InvalidTransaction)?;
+        }
+        let output_value = self.outputs
+            .iter()
+            .try_fold(0u64, |acc, output| acc.checked_add(output.value))
+            .ok_or(Error::InvalidTransaction)?;
 
-            })
-            .collect();
+        input_value.checked_sub(output_value).ok_or(Error::OutputsExceedInputs)
+    }
 
-        Ok(StandardTX {
-            inputs: new_inputs,
-            outputs: new_outputs,
-        })
+    /// The fee paid per kilobyte of the serialized transaction.
+    pub fn fee_rate<S: TXSource + ?Sized>(&self, source: &S) -> Result<f64> {
+        Ok(self.fee(source)? as f64 * 1000.0 / self.size() as f64)
     }
 
-    fn verify_input(&self, input: &TXInput, bc: &Blockchain) -> Result<()> {
+    /// The size of the serialized transaction in bytes.
+    pub fn size(&self) -> usize {
+        serialize(self).len()
+    }
+
+    /// Verifies that `input` may spend the output it refers to.
+    pub fn verify_input<S: TXSource + ?Sized>(&self, input: &TXInput, source: &S) -> Result<()> {
         use self::Error::*;
-        match bc.find_transaction(&input.txid) {
-            None => { return Err(TransactionMissing); },
-            Some(prev_tx) => {
-                match prev_tx.outputs().get(input.vout) {
-                    None => { return Err(NoTXOutput); },
-                    Some(prev_output) => {
-                        let data = TransactionData {
-                            pubkey_hash: prev_output.pubkey_hash.clone(),
-                            outputs: self.outputs.clone(),
-                        };
-                        if !data.verify(&input.signature, &input.pubkey) {
-                            return Err(PubkeySignatureMismatch);
-                        }
-                    }
+        let prev_output = prev_output(source, input)?;
+        let data = TransactionData::new(self, input, prev_output);
+
+        match prev_output.script {
+            Some(ref lock) => {
+                let unlock = input.unlock.as_ref().ok_or(ScriptFailed)?;
+                script::verify_scripts(unlock, lock, &InputChecker { data })
+            }
+            None if !prev_output.script_hash.is_empty() => {
+                let redeem_script = input.redeem_script.as_ref().ok_or(ScriptHashMismatch)?;
+                if redeem_script.hash().to_hex() != prev_output.script_hash {
+                    return Err(ScriptHashMismatch);
+                }
+                let unlock = input.unlock.as_ref().ok_or(ScriptFailed)?;
+                script::verify_scripts(unlock, redeem_script, &InputChecker { data })
+            }
+            None => {
+                let pubkey = input.pubkey.ok_or(PubkeySignatureMismatch)?;
+                if hash_public_key(&pubkey, prev_output.hash_type) != prev_output.pubkey_hash {
+                    return Err(PubkeyHashMismatch);
+                }
+                let verified = if prev_output.is_schnorr() {
+                    let signature = input.schnorr_signature.ok_or(PubkeySignatureMismatch)?;
+                    schnorr::verify(&data.id(), &signature, &pubkey)
+                } else {
+                    let signature = input.signature.ok_or(PubkeySignatureMismatch)?;
+                    data.verify(&signature, &pubkey)
+                };
+                if !verified {
+                    return Err(PubkeySignatureMismatch);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that the block rewards spent by the inputs have matured by
+    /// `height`, the height of the block holding the transaction.
+    pub fn check_maturity(&self, bc: &Blockchain, height: usize) -> Result<()> {
+        for input in self.inputs.iter() {
+            if let Some(&TX::Coinbase(_)) = bc.find_transaction(&input.txid) {
+                let coinbase_height = bc.transaction_height(&input.txid).unwrap();
+                if height < coinbase_height + COINBASE_MATURITY {
+                    return Err(Error::ImmatureCoinbase);
                 }
             }
         }
@@ -178,33 +637,180 @@ impl StandardTX {
         Ok(())
     }
 
-    pub fn verify(&self, bc: &Blockchain) -> Result<()> {
+    /// Checks that the transaction may be mined in a block at `height` whose
+    /// median time past is `time`: after its lock time, and after the
+    /// relative lock time of each input has passed since the output it
+    /// spends confirmed.
+    pub fn check_lock_times(&self, bc: &Blockchain, height: usize, time: u64) -> Result<()> {
+        let unlocked = if self.lock_time < LOCKTIME_THRESHOLD {
+            height as u64 >= self.lock_time
+        } else {
+            time >= self.lock_time
+        };
+        if !unlocked {
+            return Err(Error::TransactionLocked);
+        }
+
+        for input in self.inputs.iter().filter(|input| input.relative_lock_time != 0) {
+            let (confirmed_height, block) = bc.transaction_block(&input.txid)
+                .ok_or(Error::TransactionLocked)?;
+            let (now, unlocks_at) = if input.relative_lock_time & RELATIVE_LOCK_SECONDS != 0 {
+                let seconds = input.relative_lock_time & !RELATIVE_LOCK_SECONDS;
+                (time, (block.timestamp as u64).checked_add(seconds))
+            } else {
+                (height as u64, (confirmed_height as u64).checked_add(input.relative_lock_time))
+            };
+            // A lock that ends past the largest height or time never ends
+            if unlocks_at.is_none_or(|unlocks_at| now < unlocks_at) {
+                return Err(Error::TransactionLocked);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn verify<S: TXSource + ?Sized>(&self, source: &S) -> Result<()> {
         for input in self.inputs.iter() {
-            match self.verify_input(input, bc) {
+            match self.verify_input(input, source) {
                 Ok(_) => {},
                 Err(e) => { return Err(e); }
             }
 
         }
 
+        self.verify_outputs(source)
+    }
+
+    /// Checks the transaction apart from its inputs: the size of its output
+    /// scripts, data and memo, that unspendable outputs only carry data,
+    /// and that its outputs are not worth more than its inputs.
+    pub fn verify_outputs<S: TXSource + ?Sized>(&self, source: &S) -> Result<()> {
+        for output in self.outputs.iter() {
+            if output.script.as_ref().is_some_and(|script| script.size() > MAX_SCRIPT_SIZE) {
+                return Err(Error::ScriptLimitExceeded);
+            }
+            if output.is_unspendable() {
+                match output.payload() {
+                    Some(payload) if payload.len() > MAX_DATA_SIZE => return Err(Error::DataTooLarge),
+                    Some(_) => {}
+                    None => return Err(Error::NonstandardDataOutput),
+                }
+            }
+        }
+        if self.memo.len() > MAX_MEMO_SIZE {
+            return Err(Error::MemoTooLong);
+        }
+
+        // The outputs may not be worth more than the inputs
+        self.fee(source)?;
+
         Ok(())
     }
 }
 
+fn prev_output<'a, S: TXSource + ?Sized>(source: &'a S, input: &TXInput) -> Result<&'a TXOutput> {
+    source
+        .find_transaction(&input.txid)
+        .ok_or(Error::TransactionMissing)?
+        .outputs()
+        .get(input.vout)
+        .ok_or(Error::NoTXOutput)
+}
+
 impl TX {
     pub fn id(&self) -> String {
         let mut hash = Sha256::new();
-        match self {
-            &TX::Coinbase(ref tx) => hash.input_str(&serialize(&tx)),
-            &TX::Standard(ref tx) => hash.input_str(&serialize(&tx)),
+        match *self {
+            TX::Coinbase(ref tx) => hash.input_str(&serialize(tx)),
+            TX::Standard(ref tx) => hash.input_str(&serialize(tx)),
         };
         hash.result_str()
     }
 
     pub fn outputs(&self) -> &Vec<TXOutput> {
-        match self {
-            &TX::Standard(ref tx) => &tx.outputs,
-            &TX::Coinbase(ref tx) => &tx.outputs,
+        match *self {
+            TX::Standard(ref tx) => &tx.outputs,
+            TX::Coinbase(ref tx) => &tx.outputs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constants::MAX_SCRIPT_ELEMENT_SIZE;
+
+    struct NoTransactions;
+
+    impl TXSource for NoTransactions {
+        fn find_transaction(&self, _txid: &str) -> Option<&TX> {
+            None
+        }
+    }
+
+    // Checks a transaction without inputs paying `scripts` nothing
+    fn verify_scripts(scripts: Vec<Vec<Op>>) -> Result<()> {
+        let tx = StandardTX {
+            inputs: vec![],
+            outputs: scripts.into_iter().map(|ops| TXOutput::with_script(0, Script::new(ops))).collect(),
+            replaceable: false,
+            lock_time: 0,
+            memo: String::new(),
+        };
+        tx.verify_outputs(&NoTransactions)
+    }
+
+    #[test]
+    fn data_outputs_carry_a_single_bounded_push() {
+        assert!(verify_scripts(vec![vec![Op::Return, Op::Push(vec![0; MAX_DATA_SIZE])]]).is_ok());
+        match verify_scripts(vec![vec![Op::Return, Op::Push(vec![0; MAX_DATA_SIZE + 1])]]) {
+            Err(Error::DataTooLarge) => {}
+            other => panic!("expected DataTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_unspendable_scripts_are_rejected() {
+        for ops in [
+            vec![Op::Return],
+            vec![Op::Return, Op::Push(vec![1]), Op::Push(vec![2])],
+            vec![Op::Return, Op::Dup],
+        ] {
+            match verify_scripts(vec![ops]) {
+                Err(Error::NonstandardDataOutput) => {}
+                other => panic!("expected NonstandardDataOutput, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn time_locked_and_htlc_outputs_belong_to_their_payee() {
+        let to = Address::from_pubkey_hash(&"11".repeat(20), HashType::Hash160).unwrap();
+        let output = TXOutput::time_locked(5, &to, 1_000).unwrap();
+        assert!(output.address().is_err());
+        assert_eq!(output.time_lock(), Some((to.clone(), 1_000)));
+        assert_eq!(output.payee(), Some(to.clone()));
+        assert_eq!(TXOutput::new(5, &to).payee(), Some(to.clone()));
+
+        let sender = Address::from_pubkey_hash(&"22".repeat(20), HashType::Hash160).unwrap();
+        let htlc = Htlc::new(vec![0; 32], to.clone(), sender, 1_000).unwrap();
+        assert_eq!(TXOutput::with_script(5, htlc.script()).payee(), Some(to.clone()));
+
+        let max = i64::MAX as u64;
+        assert_eq!(TXOutput::time_locked(5, &to, max).unwrap().time_lock(), Some((to.clone(), max)));
+        match TXOutput::time_locked(5, &to, max + 1) {
+            Err(Error::LockTimeOutOfRange) => {}
+            other => panic!("expected LockTimeOutOfRange, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn output_scripts_are_bounded_in_size() {
+        let pushes = MAX_SCRIPT_SIZE / MAX_SCRIPT_ELEMENT_SIZE + 1;
+        let ops = vec![Op::Push(vec![0; MAX_SCRIPT_ELEMENT_SIZE]); pushes];
+        match verify_scripts(vec![ops]) {
+            Err(Error::ScriptLimitExceeded) => {}
+            other => panic!("expected ScriptLimitExceeded, got {:?}", other),
         }
     }
 }
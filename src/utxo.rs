@@ -1,6 +1,7 @@
 use transaction::{TX, TXOutput};
 use blockchain::Blockchain;
 use std::collections::HashMap;
+use constants::COINBASE_MATURITY;
 
 #[derive(Clone, Debug)]
 pub struct UTXOEntry {
@@ -13,11 +14,13 @@ pub type UTXOEntries = Vec<UTXOEntry>;
 pub type UTXO = HashMap<String, UTXOEntries>;
 
 
-fn new_entries(tx: &TX) -> UTXOEntries {
-    // Make a fresh collection of UTXO entries for a transaction
+pub fn new_entries(tx: &TX) -> UTXOEntries {
+    // Make a fresh collection of UTXO entries for a transaction, leaving
+    // out outputs that can never be spent
     tx.outputs()
         .iter()
         .enumerate()
+        .filter(|&(_, output)| !output.is_unspendable())
         .map(|(i, output)| {
             UTXOEntry {
                 vout: i,
@@ -40,9 +43,9 @@ pub fn find(bc: &Blockchain) -> UTXO {
     // Then, filter the UTXO set to remove outputs referenced in an input
     for (_, block) in bc.iter() {
         for tx in block.transactions.iter() {
-            match tx {
-                &TX::Coinbase(_) => (),
-                &TX::Standard(ref tx) => {
+            match *tx {
+                TX::Coinbase(_) => (),
+                TX::Standard(ref tx) => {
                     for input in tx.inputs.iter() {
                         utxo.get_mut(&input.txid)
                             .unwrap()
@@ -55,3 +58,18 @@ pub fn find(bc: &Blockchain) -> UTXO {
 
     utxo
 }
+
+/// Removes the outputs of block rewards that cannot yet be spent in the next
+/// block.
+pub fn remove_immature(bc: &Blockchain, utxo: &mut UTXO) {
+    for (depth, (_, block)) in bc.iter().enumerate() {
+        if depth + 1 >= COINBASE_MATURITY {
+            break;
+        }
+        for tx in block.transactions.iter() {
+            if let TX::Coinbase(_) = *tx {
+                utxo.remove(&tx.id());
+            }
+        }
+    }
+}
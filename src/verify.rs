@@ -0,0 +1,48 @@
+use std::cmp;
+use std::sync::OnceLock;
+use std::thread;
+use secp256k1::{ContextFlag, Secp256k1};
+use constants::MIN_INPUTS_PER_THREAD;
+use error::Result;
+use transaction::{StandardTX, TXInput, TXSource};
+
+/// The context that verifies signatures, made once and shared by every
+/// thread, as making one takes far longer than verifying a signature.
+pub fn context() -> &'static Secp256k1 {
+    static CONTEXT: OnceLock<Secp256k1> = OnceLock::new();
+    CONTEXT.get_or_init(|| Secp256k1::with_caps(ContextFlag::VerifyOnly))
+}
+
+fn verify_chunk<S: TXSource + ?Sized>(source: &S, inputs: &[(&StandardTX, &TXInput)]) -> Vec<Result<()>> {
+    inputs
+        .iter()
+        .map(|&(tx, input)| tx.verify_input(input, source))
+        .collect()
+}
+
+/// Verifies the inputs of `txs` across a pool of threads, returning the
+/// result of each input of each transaction in order, so that the first
+/// failure does not depend on which thread finished first.
+pub fn verify_inputs<S: TXSource + Sync + ?Sized>(source: &S, txs: &[&StandardTX]) -> Vec<Result<()>> {
+    let inputs: Vec<(&StandardTX, &TXInput)> = txs
+        .iter()
+        .flat_map(|&tx| tx.inputs.iter().map(move |input| (tx, input)))
+        .collect();
+
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    let chunk_size = cmp::max(MIN_INPUTS_PER_THREAD, inputs.len().div_ceil(threads));
+    if inputs.len() <= chunk_size {
+        return verify_chunk(source, &inputs);
+    }
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = inputs
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || verify_chunk(source, chunk)))
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    })
+}
@@ -1,30 +1,283 @@
 use secp256k1::key::{SecretKey, PublicKey};
-use secp256k1::Secp256k1;
+use secp256k1::{ContextFlag, Message, Secp256k1};
 use rand::OsRng;
 use crypto::digest::Digest;
+use crypto::ripemd160::Ripemd160;
 use crypto::sha2::Sha256;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde_json;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::env;
+use std::mem;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{SystemTime, UNIX_EPOCH};
 use serialize::{serialize, deserialize};
-use constants::WALLETS;
+use constants::{WALLETS, WALLETS_SESSION, WALLETS_SESSION_VAR, MNEMONIC_WORDS, WIF_VERSION, MAX_MULTISIG_KEYS};
+use coinselect::Strategy;
+use encryption::{self, KdfParams, KEY_SIZE};
+use error::{Error, Result};
+use hd::{self, ExtendedPrivKey, ExtendedPubKey, HARDENED};
+use mnemonic;
+use blockchain::Blockchain;
+use address::Address;
+use base58;
+use script::Script;
 
+const PURPOSE: u32 = 44;
+// The coin type of the BIP44 path of new seeds. It is not Bitcoin's, so that
+// a mnemonic phrase also used in a Bitcoin wallet derives different keys
+const COIN_TYPE: u32 = 0x7261;
+// The coin type seeds used before they had their own, shared with Bitcoin
+const LEGACY_COIN_TYPE: u32 = 0;
+// The last byte of a WIF key says how its address is hashed
+const WIF_SHA256: u8 = 0x00;
+const WIF_HASH160: u8 = 0x01;
 
-#[derive(Serialize, Deserialize)]
+
+/// How a public key is hashed into the public key hash that outputs are
+/// locked to. Older wallets and outputs use a single SHA-256; newer ones
+/// use the shorter RIPEMD-160 of the SHA-256.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum HashType {
+    #[default]
+    Sha256,
+    Hash160,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Wallet {
-    pub secret_key: SecretKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret_key: Option<SecretKey>,
+    // The secret key encrypted with the master key of an encrypted wallet file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_key: Option<String>,
     pub public_key: PublicKey,
+    #[serde(default)]
+    pub hash_type: HashType,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// The derivation path of a key derived from the HD seed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Whether the wallet receives coins on its Schnorr address.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub schnorr: bool,
+}
+
+/// An address whose coins are tracked without holding its secret key.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WatchOnly {
+    pub address: Address,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PublicKey>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Encryption {
+    kdf: KdfParams,
+    salt: String,
+    // The master key, encrypted with the key derived from the passphrase
+    master_key: String,
+}
+
+// The seed from which keys are deterministically derived
+#[derive(Serialize, Deserialize, Clone)]
+struct HDSeed {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_seed: Option<String>,
+    // The mnemonic phrase the seed was made from, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_mnemonic: Option<String>,
+    #[serde(default)]
+    account: u32,
+    // Seeds saved without a coin type derive their keys with the legacy one
+    #[serde(default)]
+    coin_type: u32,
+    // The next child index to use on the external and internal chains
+    #[serde(default)]
+    next_index: [u32; 2],
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct Wallets(HashMap<String, Wallet>);
+struct ExportedKey {
+    address: Address,
+    wif: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(default)]
+    strategy: Strategy,
+    #[serde(default, skip_serializing_if = "is_false")]
+    schnorr: bool,
+}
 
-pub fn hash_public_key(pubkey: &PublicKey) -> String {
-    let secp = Secp256k1::new();
+impl ExportedKey {
+    fn new(wallet: &Wallet) -> Result<ExportedKey> {
+        Ok(ExportedKey {
+            address: wallet.address(),
+            wif: wallet.to_wif()?,
+            path: wallet.path.clone(),
+            strategy: wallet.strategy,
+            schnorr: wallet.schnorr,
+        })
+    }
+
+    // The wallet of the key, checking that it has the exported address
+    fn to_wallet(&self) -> Result<Wallet> {
+        let mut wallet = Wallet::from_wif(&self.wif)?;
+        wallet.schnorr = self.schnorr;
+        if wallet.address() != self.address {
+            return Err(Error::InvalidPrivateKey);
+        }
+        wallet.path = self.path.clone();
+        wallet.strategy = self.strategy;
+        Ok(wallet)
+    }
+}
+
+/// A portable, unencrypted copy of the keys, HD seed, watch-only addresses
+/// and multisig scripts of a wallet file.
+#[derive(Serialize, Deserialize)]
+pub struct Export {
+    keys: Vec<ExportedKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hd: Option<HDSeed>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    watch_only: Vec<WatchOnly>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scripts: Vec<Script>,
+}
+
+impl Export {
+    /// Writes the export to `path`, readable and writable by its owner
+    /// only, as the keys in it are not encrypted.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        create_private(path)?.write_all(serialize(self).as_bytes())
+    }
+}
+
+// The wallet file as stored on disk
+#[derive(Serialize, Deserialize)]
+struct StoredWallets {
+    wallets: HashMap<String, Wallet>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<Encryption>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hd: Option<HDSeed>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    watch_only: HashMap<String, WatchOnly>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    scripts: HashMap<String, Script>,
+}
+
+// An unlocked master key, kept until it expires, encrypted with a session
+// token that is only held in the environment
+#[derive(Serialize, Deserialize)]
+struct Session {
+    master_key: String,
+    expires: u64,
+}
+
+pub struct Wallets {
+    // Keyed by the hex public key hash of each wallet
+    wallets: HashMap<String, Wallet>,
+    encryption: Option<Encryption>,
+    hd: Option<HDSeed>,
+    // Also keyed by public key hash
+    watch_only: HashMap<String, WatchOnly>,
+    // The scripts of multisig addresses, keyed by the hex script hash
+    scripts: HashMap<String, Script>,
+    master_key: Option<Vec<u8>>,
+}
+
+impl HashType {
+    /// The size of the hash in bytes.
+    pub fn size(&self) -> usize {
+        match *self {
+            HashType::Sha256 => 32,
+            HashType::Hash160 => 20,
+        }
+    }
+
+    pub fn is_sha256(&self) -> bool {
+        *self == HashType::Sha256
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut result = [0; 32];
     let mut hash = Sha256::new();
-    hash.input(&pubkey.serialize_vec(&secp, true)[..]);
+    hash.input(data);
+    hash.result(&mut result);
+    result
+}
+
+/// The RIPEMD-160 of the SHA-256 of `data`.
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    let mut result = [0; 20];
+    let mut hash = Ripemd160::new();
+    hash.input(&sha256(data));
+    hash.result(&mut result);
+    result
+}
+
+/// The compressed serialization of a public key.
+pub fn serialize_public_key(pubkey: &PublicKey) -> Vec<u8> {
+    let secp = Secp256k1::with_caps(ContextFlag::None);
+    let serialized = pubkey.serialize_vec(&secp, true);
+    let bytes = serialized.to_vec();
+    // The ArrayVec of secp256k1's arrayvec 0.3 reads past its length when
+    // dropped, which debug builds abort on
+    mem::forget(serialized);
+    bytes
+}
+
+/// The hex hash of the compressed public key.
+pub fn hash_public_key(pubkey: &PublicKey, hash_type: HashType) -> String {
+    let serialized = serialize_public_key(pubkey);
+
+    match hash_type {
+        HashType::Sha256 => sha256(&serialized).to_hex(),
+        HashType::Hash160 => hash160(&serialized).to_hex(),
+    }
+}
+
+// Encrypts with the master key, returning hex
+fn seal(master_key: &[u8], plaintext: &[u8], aad: &[u8]) -> String {
+    encryption::encrypt(master_key, plaintext, aad).to_hex()
+}
+
+fn unseal(master_key: &[u8], sealed: &str, aad: &[u8]) -> Result<Vec<u8>> {
+    let sealed = sealed.from_hex().map_err(|_| Error::IncorrectPassphrase)?;
+    encryption::decrypt(master_key, &sealed, aad).ok_or(Error::IncorrectPassphrase)
+}
+
+// Creates the file at `path`, readable and writable by its owner only
+fn create_private(path: &str) -> io::Result<File> {
+    let _ = fs::remove_file(path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
 
-    hash.result_str()
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 impl Wallet {
@@ -32,43 +285,696 @@ impl Wallet {
         let secp = Secp256k1::new();
         let pair = secp.generate_keypair(&mut OsRng::new().unwrap()).unwrap();
 
+        Wallet::from_secret_key(pair.0)
+    }
+
+    pub fn from_secret_key(secret_key: SecretKey) -> Wallet {
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key).unwrap();
+
         Wallet {
-            secret_key: pair.0,
-            public_key: pair.1,
+            secret_key: Some(secret_key),
+            encrypted_key: None,
+            public_key,
+            hash_type: HashType::Hash160,
+            strategy: Strategy::default(),
+            path: None,
+            schnorr: false,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        let address = Address::from_public_key(&self.public_key, self.hash_type);
+        match address.to_schnorr() {
+            Ok(schnorr) if self.schnorr => schnorr,
+            _ => address,
+        }
+    }
+
+    pub fn pubkey_hash(&self) -> String {
+        hash_public_key(&self.public_key, self.hash_type)
+    }
+
+    /// The secret key in Wallet Import Format: Base58Check of the network's
+    /// version byte, the key, and a byte for how the address is hashed.
+    pub fn to_wif(&self) -> Result<String> {
+        let mut data = vec![WIF_VERSION];
+        data.extend_from_slice(&self.secret_key()?[..]);
+        data.push(match self.hash_type {
+            HashType::Sha256 => WIF_SHA256,
+            HashType::Hash160 => WIF_HASH160,
+        });
+
+        Ok(base58::encode_check(&data))
+    }
+
+    pub fn from_wif(wif: &str) -> Result<Wallet> {
+        let data = base58::decode_check(wif).ok_or(Error::InvalidPrivateKey)?;
+        if data.len() != 34 || data[0] != WIF_VERSION {
+            return Err(Error::InvalidPrivateKey);
+        }
+        let hash_type = match data[33] {
+            WIF_SHA256 => HashType::Sha256,
+            WIF_HASH160 => HashType::Hash160,
+            _ => return Err(Error::InvalidPrivateKey),
+        };
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &data[1..33])
+            .map_err(|_| Error::InvalidPrivateKey)?;
+
+        let mut wallet = Wallet::from_secret_key(secret_key);
+        wallet.hash_type = hash_type;
+        Ok(wallet)
+    }
+
+    /// The secret key, unless the wallet is encrypted and locked.
+    pub fn secret_key(&self) -> Result<&SecretKey> {
+        self.secret_key.as_ref().ok_or(Error::WalletLocked)
+    }
+
+    /// Signs a 32-byte digest, returning the DER signature that scripts
+    /// check.
+    pub fn sign_digest(&self, digest: &[u8]) -> Result<Vec<u8>> {
+        let secp = Secp256k1::new();
+        let msg = Message::from_slice(digest).unwrap();
+        let signature = secp.sign(&msg, self.secret_key()?).unwrap();
+        Ok(signature.serialize_der(&secp))
+    }
+
+    fn aad(&self) -> Vec<u8> {
+        serialize_public_key(&self.public_key)
+    }
+
+    fn encrypt(&mut self, master_key: &[u8]) -> Result<()> {
+        self.encrypted_key = Some(seal(master_key, &self.secret_key()?[..], &self.aad()));
+        Ok(())
+    }
+
+    fn decrypt(&mut self, master_key: &[u8]) -> Result<()> {
+        if let Some(ref encrypted_key) = self.encrypted_key {
+            let bytes = unseal(master_key, encrypted_key, &self.aad())?;
+            let secp = Secp256k1::new();
+            let secret_key = SecretKey::from_slice(&secp, &bytes)
+                .map_err(|_| Error::IncorrectPassphrase)?;
+            self.secret_key = Some(secret_key);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Wallet {
+    fn default() -> Wallet {
+        Wallet::new()
+    }
+}
+
+impl Encryption {
+    fn new(passphrase: &str, master_key: &[u8]) -> Encryption {
+        Encryption::with_kdf(passphrase, master_key, KdfParams::default())
+    }
+
+    fn with_kdf(passphrase: &str, master_key: &[u8], kdf: KdfParams) -> Encryption {
+        let salt = encryption::random_bytes(16);
+        let key = encryption::derive_key(passphrase, &salt, &kdf);
+
+        Encryption {
+            kdf,
+            salt: salt.to_hex(),
+            master_key: encryption::encrypt(&key, master_key, &[]).to_hex(),
+        }
+    }
+
+    fn master_key(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let salt = self.salt.from_hex().map_err(|_| Error::IncorrectPassphrase)?;
+        let sealed = self.master_key.from_hex().map_err(|_| Error::IncorrectPassphrase)?;
+        let key = encryption::derive_key(passphrase, &salt, &self.kdf);
+
+        encryption::decrypt(&key, &sealed, &[]).ok_or(Error::IncorrectPassphrase)
+    }
+}
+
+impl HDSeed {
+    fn encrypt(&mut self, master_key: &[u8]) -> Result<()> {
+        let seed = self.seed.as_ref().ok_or(Error::NoHDSeed)?.from_hex().unwrap();
+        self.encrypted_seed = Some(seal(master_key, &seed, b"seed"));
+        if let Some(ref mnemonic) = self.mnemonic {
+            self.encrypted_mnemonic = Some(seal(master_key, mnemonic.as_bytes(), b"mnemonic"));
+        }
+        Ok(())
+    }
+
+    fn decrypt(&mut self, master_key: &[u8]) -> Result<()> {
+        if let Some(ref encrypted_seed) = self.encrypted_seed {
+            self.seed = Some(unseal(master_key, encrypted_seed, b"seed")?.to_hex());
+        }
+        if let Some(ref encrypted_mnemonic) = self.encrypted_mnemonic {
+            let mnemonic = unseal(master_key, encrypted_mnemonic, b"mnemonic")?;
+            self.mnemonic = Some(String::from_utf8(mnemonic).map_err(|_| Error::IncorrectPassphrase)?);
+        }
+        Ok(())
+    }
+
+    // Forgets the plaintext seed and mnemonic of an encrypted seed
+    fn strip(&mut self) {
+        if self.encrypted_seed.is_some() {
+            self.seed = None;
+            self.mnemonic = None;
         }
     }
 }
 
 impl Wallets {
     pub fn save(&self) {
+        // Only the encrypted secret keys of an encrypted wallet are written
+        let mut wallets = self.wallets.clone();
+        if self.encryption.is_some() {
+            for wallet in wallets.values_mut() {
+                wallet.secret_key = None;
+            }
+        }
+        let mut hd = self.hd.clone();
+        if let Some(ref mut hd) = hd {
+            hd.strip();
+        }
+        let stored = StoredWallets {
+            wallets,
+            encryption: self.encryption.clone(),
+            hd,
+            watch_only: self.watch_only.clone(),
+            scripts: self.scripts.clone(),
+        };
+
         let mut file = File::create(WALLETS).unwrap();
-        file.write_all(serialize(self).as_bytes()).unwrap()
+        file.write_all(serialize(&stored).as_bytes()).unwrap()
     }
 
     pub fn open() -> Wallets {
         let file = File::open(WALLETS);
 
-        match file {
+        let stored = match file {
             Ok(mut f) => {
                 let mut contents = String::new();
                 f.read_to_string(&mut contents).unwrap();
 
-                deserialize(&contents)
+                // Older wallet files are a bare map of addresses to wallets
+                serde_json::from_str(&contents).unwrap_or_else(|_| {
+                    StoredWallets {
+                        wallets: deserialize(&contents),
+                        encryption: None,
+                        hd: None,
+                        watch_only: HashMap::new(),
+                        scripts: HashMap::new(),
+                    }
+                })
             },
             Err(_) => {
-                Wallets(HashMap::new())
+                StoredWallets {
+                    wallets: HashMap::new(),
+                    encryption: None,
+                    hd: None,
+                    watch_only: HashMap::new(),
+                    scripts: HashMap::new(),
+                }
             }
+        };
+
+        let mut wallets = Wallets {
+            wallets: stored.wallets,
+            encryption: stored.encryption,
+            hd: stored.hd,
+            watch_only: stored.watch_only,
+            scripts: stored.scripts,
+            master_key: None,
+        };
+        wallets.resume_session();
+
+        wallets
+    }
+
+    // Unlocks the wallets with the master key saved by `unlock`, if it has
+    // not expired and the session token is set in the environment
+    fn resume_session(&mut self) {
+        if self.encryption.is_none() {
+            return;
+        }
+        let contents = match fs::read_to_string(WALLETS_SESSION) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+        let session: Session = match serde_json::from_str(&contents) {
+            Ok(session) => session,
+            Err(_) => return self.lock(),
+        };
+        if session.expires <= now() {
+            return self.lock();
+        }
+
+        let token = match env::var(WALLETS_SESSION_VAR).ok().and_then(|token| token.from_hex().ok()) {
+            Some(ref token) if token.len() == KEY_SIZE => token.clone(),
+            _ => return,
+        };
+        let master_key = session.master_key
+            .from_hex()
+            .ok()
+            .and_then(|sealed| encryption::decrypt(&token, &sealed, &session.expires.to_be_bytes()));
+        if let Some(master_key) = master_key {
+            if self.decrypt_all(master_key).is_err() {
+                self.lock();
+            }
+        }
+    }
+
+    fn decrypt_all(&mut self, master_key: Vec<u8>) -> Result<()> {
+        for wallet in self.wallets.values_mut() {
+            wallet.decrypt(&master_key)?;
+        }
+        if let Some(ref mut hd) = self.hd {
+            hd.decrypt(&master_key)?;
         }
+        self.master_key = Some(master_key);
+        Ok(())
+    }
 
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
     }
 
-    pub fn add(&mut self, wallet: Wallet) {
-        let address = hash_public_key(&wallet.public_key);
-        self.0.insert(address, wallet);
+    pub fn is_locked(&self) -> bool {
+        self.encryption.is_some() && self.master_key.is_none()
     }
 
-    pub fn get(&self, address: &str) -> Option<&Wallet> {
-        self.0.get(address)
+    /// Encrypts every secret key with a new master key, itself encrypted
+    /// with a key derived from `passphrase`. The wallets are left locked.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.encryption.is_some() {
+            return Err(Error::WalletEncrypted);
+        }
+
+        let master_key = encryption::random_bytes(KEY_SIZE);
+        for wallet in self.wallets.values_mut() {
+            wallet.encrypt(&master_key)?;
+        }
+        if let Some(ref mut hd) = self.hd {
+            hd.encrypt(&master_key)?;
+        }
+        self.encryption = Some(Encryption::new(passphrase, &master_key));
+        self.lock();
+
+        Ok(())
+    }
+
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<()> {
+        let master_key = match self.encryption {
+            Some(ref encryption) => encryption.master_key(old)?,
+            None => return Err(Error::WalletNotEncrypted),
+        };
+        self.encryption = Some(Encryption::new(new, &master_key));
+
+        Ok(())
+    }
+
+    /// Decrypts the secret keys for `timeout` seconds, across runs that
+    /// set the returned hex session token in `WALLETS_SESSION_VAR`. Only
+    /// the master key encrypted with the token is written to disk.
+    pub fn unlock(&mut self, passphrase: &str, timeout: u64) -> Result<String> {
+        let master_key = match self.encryption {
+            Some(ref encryption) => encryption.master_key(passphrase)?,
+            None => return Err(Error::WalletNotEncrypted),
+        };
+        self.decrypt_all(master_key.clone())?;
+
+        let token = encryption::random_bytes(KEY_SIZE);
+        let expires = now().saturating_add(timeout);
+        let session = Session {
+            master_key: encryption::encrypt(&token, &master_key, &expires.to_be_bytes()).to_hex(),
+            expires,
+        };
+        let mut file = create_private(WALLETS_SESSION).unwrap();
+        file.write_all(serialize(&session).as_bytes()).unwrap();
+
+        Ok(token.to_hex())
+    }
+
+    /// Forgets the decrypted secret keys of an encrypted wallet file.
+    pub fn lock(&mut self) {
+        if self.encryption.is_none() {
+            return;
+        }
+        let _ = fs::remove_file(WALLETS_SESSION);
+        self.master_key = None;
+        for wallet in self.wallets.values_mut() {
+            wallet.secret_key = None;
+        }
+        if let Some(ref mut hd) = self.hd {
+            hd.strip();
+        }
+    }
+
+    pub fn add(&mut self, mut wallet: Wallet) -> Result<()> {
+        if self.encryption.is_some() {
+            let master_key = self.master_key.as_ref().ok_or(Error::WalletLocked)?;
+            wallet.encrypt(master_key)?;
+        }
+        self.wallets.insert(wallet.pubkey_hash(), wallet);
+
+        Ok(())
+    }
+
+    fn init_hd(&mut self, seed: &[u8], mnemonic: Option<String>) -> Result<()> {
+        if self.hd.is_some() {
+            return Err(Error::HDSeedExists);
+        }
+        let mut hd = HDSeed {
+            seed: Some(seed.to_hex()),
+            encrypted_seed: None,
+            mnemonic,
+            encrypted_mnemonic: None,
+            account: 0,
+            coin_type: COIN_TYPE,
+            next_index: [0, 0],
+        };
+        if self.encryption.is_some() {
+            hd.encrypt(self.master_key.as_ref().ok_or(Error::WalletLocked)?)?;
+        }
+        self.hd = Some(hd);
+
+        Ok(())
+    }
+
+    /// Sets the seed that new keys are derived from.
+    pub fn set_seed(&mut self, seed: &[u8]) -> Result<()> {
+        self.init_hd(seed, None)
+    }
+
+    /// Sets the seed to the one stretched from a mnemonic phrase and an
+    /// optional passphrase, keeping the phrase so it can be shown again.
+    pub fn set_mnemonic(&mut self, phrase: &str, passphrase: &str) -> Result<()> {
+        let seed = mnemonic::to_seed(phrase, passphrase)?;
+        self.init_hd(&seed, Some(mnemonic::normalize(phrase)))
+    }
+
+    /// The mnemonic phrase the HD seed was made from.
+    pub fn mnemonic(&self) -> Result<&str> {
+        let hd = self.hd.as_ref().ok_or(Error::NoHDSeed)?;
+        match hd.mnemonic {
+            Some(ref mnemonic) => Ok(mnemonic),
+            None if hd.encrypted_mnemonic.is_some() => Err(Error::WalletLocked),
+            None => Err(Error::NoMnemonic),
+        }
+    }
+
+    fn master(&self) -> Result<ExtendedPrivKey> {
+        let hd = self.hd.as_ref().ok_or(Error::NoHDSeed)?;
+        let seed = hd.seed.as_ref().ok_or(Error::WalletLocked)?;
+        ExtendedPrivKey::new_master(&seed.from_hex().unwrap())
+    }
+
+    fn account_path(&self) -> Vec<u32> {
+        let (coin_type, account) = self.hd.as_ref().map_or((COIN_TYPE, 0), |hd| (hd.coin_type, hd.account));
+        vec![PURPOSE + HARDENED, coin_type + HARDENED, account + HARDENED]
+    }
+
+    /// The extended public key of the account, from which its addresses can
+    /// be derived without the seed.
+    pub fn account_xpub(&self) -> Result<ExtendedPubKey> {
+        Ok(self.master()?.derive_path(&self.account_path())?.to_extended_pub())
+    }
+
+    // Derives the key at `index` on the external (0) or internal (1) chain
+    fn derive(&self, chain: u32, index: u32) -> Result<Wallet> {
+        let mut path = self.account_path();
+        path.push(chain);
+        path.push(index);
+        let key = self.master()?.derive_path(&path)?;
+
+        let mut wallet = Wallet::from_secret_key(key.secret_key);
+        wallet.path = Some(hd::format_path(&path));
+        Ok(wallet)
+    }
+
+    /// Derives a new receiving address from the HD seed, making a seed from
+    /// a new mnemonic phrase first if there is none.
+    pub fn new_address(&mut self) -> Result<Address> {
+        if self.hd.is_none() {
+            self.set_mnemonic(&mnemonic::generate(MNEMONIC_WORDS)?, "")?;
+        }
+        let index = self.hd.as_ref().unwrap().next_index[0];
+        let wallet = self.derive(0, index)?;
+        let address = wallet.address();
+
+        self.add(wallet)?;
+        self.hd.as_mut().unwrap().next_index[0] = index + 1;
+
+        Ok(address)
+    }
+
+    /// Derives addresses on both chains until `gap_limit` addresses in a row
+    /// have never received coins, adding those that have. Returns the number
+    /// of used addresses found. A seed with none is tried with the legacy
+    /// coin type, in case it was used before seeds had their own.
+    pub fn discover(&mut self, bc: &Blockchain, gap_limit: u32) -> Result<usize> {
+        let used: HashSet<String> = bc.iter()
+            .flat_map(|(_, block)| block.transactions.iter())
+            .flat_map(|tx| tx.outputs().iter())
+            .map(|output| output.pubkey_hash.clone())
+            .collect();
+
+        let found = self.discover_used(&used, gap_limit)?;
+        let coin_type = self.hd.as_ref().ok_or(Error::NoHDSeed)?.coin_type;
+        if found > 0 || coin_type == LEGACY_COIN_TYPE {
+            return Ok(found);
+        }
+
+        self.hd.as_mut().unwrap().coin_type = LEGACY_COIN_TYPE;
+        let found = self.discover_used(&used, gap_limit)?;
+        if found == 0 {
+            self.hd.as_mut().unwrap().coin_type = coin_type;
+        }
+
+        Ok(found)
+    }
+
+    // Adds the derived keys whose public key hashes are in `used`
+    fn discover_used(&mut self, used: &HashSet<String>, gap_limit: u32) -> Result<usize> {
+        let mut found = 0;
+        for chain in 0..2 {
+            let mut index = 0;
+            let mut unused = 0;
+            while unused < gap_limit {
+                let mut wallet = self.derive(chain, index)?;
+                // Keys derived before Hash160 addresses were paid with SHA-256
+                if !used.contains(&wallet.pubkey_hash()) {
+                    wallet.hash_type = HashType::Sha256;
+                }
+                let pubkey_hash = wallet.pubkey_hash();
+                if used.contains(&pubkey_hash) {
+                    if !self.wallets.contains_key(&pubkey_hash) {
+                        self.add(wallet)?;
+                    }
+                    let hd = self.hd.as_mut().unwrap();
+                    hd.next_index[chain as usize] = hd.next_index[chain as usize].max(index + 1);
+                    found += 1;
+                    unused = 0;
+                } else {
+                    unused += 1;
+                }
+                index += 1;
+            }
+        }
+
+        Ok(found)
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&Wallet> {
+        self.wallets.get(&address.pubkey_hash())
+    }
+
+    pub fn get_mut(&mut self, address: &Address) -> Option<&mut Wallet> {
+        self.wallets.get_mut(&address.pubkey_hash())
+    }
+
+    /// The addresses of every wallet, watch-only entry and multisig script.
+    /// Wallets receiving on their Schnorr address also list their ECDSA
+    /// address, which may still hold coins.
+    pub fn addresses(&self) -> Vec<Address> {
+        self.wallets
+            .values()
+            .flat_map(|wallet| {
+                let legacy = Address::from_public_key(&wallet.public_key, wallet.hash_type);
+                if wallet.schnorr {
+                    vec![legacy, wallet.address()]
+                } else {
+                    vec![legacy]
+                }
+            })
+            .chain(self.watch_only.values().map(|watch_only| watch_only.address.clone()))
+            .chain(self.scripts.values().map(Address::from_script))
+            .collect()
+    }
+
+    /// The wallet that can sign for `address`.
+    pub fn signer(&self, address: &Address) -> Result<&Wallet> {
+        match self.get(address) {
+            Some(wallet) => Ok(wallet),
+            None if self.is_watch_only(address) => Err(Error::WatchOnlyAddress),
+            None => Err(Error::NoWalletForAddress),
+        }
+    }
+
+    pub fn is_watch_only(&self, address: &Address) -> bool {
+        self.watch_only.contains_key(&address.pubkey_hash())
+    }
+
+    /// Watches an address without its secret key.
+    pub fn import_address(&mut self, address: Address) -> Result<()> {
+        self.watch(WatchOnly {
+            address,
+            public_key: None,
+        })
+    }
+
+    /// Watches the address of a public key without its secret key.
+    pub fn import_public_key(&mut self, public_key: PublicKey) -> Result<Address> {
+        let address = Address::from_public_key(&public_key, HashType::Hash160);
+        self.watch(WatchOnly {
+            address: address.clone(),
+            public_key: Some(public_key),
+        })?;
+
+        Ok(address)
+    }
+
+    fn watch(&mut self, watch_only: WatchOnly) -> Result<()> {
+        let pubkey_hash = watch_only.address.pubkey_hash();
+        if self.wallets.contains_key(&pubkey_hash) || self.watch_only.contains_key(&pubkey_hash) {
+            return Err(Error::AddressExists);
+        }
+        self.watch_only.insert(pubkey_hash, watch_only);
+
+        Ok(())
+    }
+
+    /// Adds the address of a multisig script needing `required` signatures
+    /// from `public_keys`, in the order given.
+    pub fn add_multisig(&mut self, required: usize, public_keys: &[PublicKey]) -> Result<Address> {
+        if required == 0 || required > public_keys.len() || public_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(Error::InvalidMultisig);
+        }
+        let public_keys: Vec<Vec<u8>> = public_keys.iter().map(serialize_public_key).collect();
+        let script = Script::multisig(required, &public_keys);
+
+        let address = Address::from_script(&script);
+        if self.scripts.contains_key(&script.hash().to_hex()) {
+            return Err(Error::AddressExists);
+        }
+        self.scripts.insert(script.hash().to_hex(), script);
+
+        Ok(address)
+    }
+
+    /// The script of a multisig address in the wallet.
+    pub fn redeem_script(&self, address: &Address) -> Option<&Script> {
+        address.script_hash().and_then(|script_hash| self.scripts.get(&script_hash))
+    }
+
+    /// Adds a wallet made from an imported secret key, replacing any
+    /// watch-only entry for its address.
+    pub fn import_key(&mut self, wallet: Wallet) -> Result<Address> {
+        let pubkey_hash = wallet.pubkey_hash();
+        if self.wallets.contains_key(&pubkey_hash) {
+            return Err(Error::AddressExists);
+        }
+        let address = wallet.address();
+        self.add(wallet)?;
+        self.watch_only.remove(&pubkey_hash);
+
+        Ok(address)
+    }
+
+    /// Copies every key, the HD seed, the watch-only addresses and the
+    /// multisig scripts, which needs the wallet file to be unlocked.
+    pub fn export(&self) -> Result<Export> {
+        let mut keys = Vec::new();
+        for wallet in self.wallets.values() {
+            keys.push(ExportedKey::new(wallet)?);
+        }
+        let hd = match self.hd {
+            Some(ref hd) if hd.seed.is_none() => return Err(Error::WalletLocked),
+            Some(ref hd) => Some(HDSeed {
+                encrypted_seed: None,
+                encrypted_mnemonic: None,
+                ..hd.clone()
+            }),
+            None => None,
+        };
+
+        Ok(Export {
+            keys,
+            hd,
+            watch_only: self.watch_only.values().cloned().collect(),
+            scripts: self.scripts.values().cloned().collect(),
+        })
+    }
+
+    /// Adds the keys, watch-only addresses and multisig scripts of an export
+    /// that are not already in the wallet, and its HD seed if the wallet has
+    /// none. Returns the addresses added.
+    pub fn import(&mut self, export: Export) -> Result<Vec<Address>> {
+        let mut imported = Vec::new();
+        for key in export.keys {
+            let wallet = key.to_wallet()?;
+            if self.wallets.contains_key(&wallet.pubkey_hash()) {
+                continue;
+            }
+            imported.push(self.import_key(wallet)?);
+        }
+        for watch_only in export.watch_only {
+            let address = watch_only.address.clone();
+            if self.watch(watch_only).is_ok() {
+                imported.push(address);
+            }
+        }
+        for script in export.scripts {
+            let address = Address::from_script(&script);
+            if let Entry::Vacant(entry) = self.scripts.entry(script.hash().to_hex()) {
+                entry.insert(script);
+                imported.push(address);
+            }
+        }
+
+        if let (None, Some(hd)) = (self.hd.as_ref(), export.hd) {
+            let seed = hd.seed
+                .as_ref()
+                .and_then(|seed| seed.from_hex().ok())
+                .ok_or(Error::InvalidWalletExport)?;
+            self.init_hd(&seed, hd.mnemonic)?;
+            let own = self.hd.as_mut().unwrap();
+            own.account = hd.account;
+            own.coin_type = hd.coin_type;
+            own.next_index = hd.next_index;
+        }
+
+        Ok(imported)
+    }
+
+    /// The wallet holding `public_key`, whichever way its address is hashed.
+    pub fn find_by_public_key(&self, public_key: &PublicKey) -> Option<&Wallet> {
+        self.wallets.values().find(|wallet| wallet.public_key == *public_key)
+    }
+}
+
+#[cfg(test)]
+impl Wallets {
+    /// Wallets holding no keys, which are not saved when dropped.
+    pub fn empty() -> ::std::mem::ManuallyDrop<Wallets> {
+        ::std::mem::ManuallyDrop::new(Wallets {
+            wallets: HashMap::new(),
+            encryption: None,
+            hd: None,
+            watch_only: HashMap::new(),
+            scripts: HashMap::new(),
+            master_key: None,
+        })
     }
 }
 
@@ -77,3 +983,160 @@ impl Drop for Wallets {
         self.save();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transaction::{StandardTX, TXOutput, TX};
+
+    #[test]
+    fn exported_schnorr_keys_import_with_their_address() {
+        let mut wallet = Wallet::new();
+        wallet.schnorr = true;
+        wallet.strategy = Strategy::SmallestFirst;
+        let key: ExportedKey = deserialize(&serialize(&ExportedKey::new(&wallet).unwrap()));
+
+        let imported = key.to_wallet().unwrap();
+        assert!(imported.schnorr);
+        assert!(imported.address().is_schnorr());
+        assert_eq!(imported.address(), wallet.address());
+        assert_eq!(imported.strategy, Strategy::SmallestFirst);
+    }
+
+    // Cheap scrypt parameters, as the tests do not need the default cost
+    const TEST_KDF: KdfParams = KdfParams { log_n: 4, r: 8, p: 1 };
+
+    fn assert_incorrect_passphrase<T>(result: Result<T>) {
+        match result {
+            Err(Error::IncorrectPassphrase) => {}
+            Err(err) => panic!("expected IncorrectPassphrase, got {:?}", err),
+            Ok(_) => panic!("expected IncorrectPassphrase"),
+        }
+    }
+
+    #[test]
+    fn the_master_key_needs_the_passphrase() {
+        let master_key = encryption::random_bytes(KEY_SIZE);
+        let encryption = Encryption::with_kdf("correct horse", &master_key, TEST_KDF);
+        assert_eq!(encryption.master_key("correct horse").unwrap(), master_key);
+        assert_incorrect_passphrase(encryption.master_key("battery staple"));
+
+        let mut tampered = encryption.clone();
+        let mut sealed = tampered.master_key.from_hex().unwrap();
+        sealed[10] ^= 1;
+        tampered.master_key = sealed.to_hex();
+        assert_incorrect_passphrase(tampered.master_key("correct horse"));
+    }
+
+    #[test]
+    fn encrypted_keys_decrypt_with_the_master_key_only() {
+        let master_key = encryption::random_bytes(KEY_SIZE);
+        let mut wallet = Wallet::new();
+        let secret_key = *wallet.secret_key().unwrap();
+        wallet.encrypt(&master_key).unwrap();
+        wallet.secret_key = None;
+        assert!(wallet.secret_key().is_err());
+
+        assert_incorrect_passphrase(wallet.clone().decrypt(&encryption::random_bytes(KEY_SIZE)));
+        wallet.decrypt(&master_key).unwrap();
+        assert_eq!(*wallet.secret_key().unwrap(), secret_key);
+    }
+
+    #[test]
+    fn encrypted_keys_are_bound_to_their_wallet() {
+        let master_key = encryption::random_bytes(KEY_SIZE);
+        let mut wallet = Wallet::new();
+        wallet.encrypt(&master_key).unwrap();
+
+        let mut other = Wallet::new();
+        other.secret_key = None;
+        other.encrypted_key = wallet.encrypted_key.clone();
+        assert_incorrect_passphrase(other.decrypt(&master_key));
+
+        let mut sealed = wallet.encrypted_key.as_ref().unwrap().from_hex().unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        wallet.encrypted_key = Some(sealed.to_hex());
+        assert_incorrect_passphrase(wallet.decrypt(&master_key));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exports_are_private_to_their_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("raicoin-export-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let export = Export {
+            keys: vec![ExportedKey::new(&Wallet::new()).unwrap()],
+            hd: None,
+            watch_only: vec![],
+            scripts: vec![],
+        };
+        export.write(path).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        let written: Export = deserialize(&fs::read_to_string(path).unwrap());
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(written.keys[0].wif, export.keys[0].wif);
+    }
+
+    #[test]
+    fn exported_keys_must_match_their_address() {
+        let mut key = ExportedKey::new(&Wallet::new()).unwrap();
+        key.address = Wallet::new().address();
+        match key.to_wallet() {
+            Err(Error::InvalidPrivateKey) => {}
+            other => panic!("expected InvalidPrivateKey, got {:?}", other.map(|w| w.address())),
+        }
+    }
+
+    const SEED: [u8; 32] = [7; 32];
+
+    fn seeded(coin_type: u32) -> ::std::mem::ManuallyDrop<Wallets> {
+        let mut wallets = Wallets::empty();
+        wallets.set_seed(&SEED).unwrap();
+        wallets.hd.as_mut().unwrap().coin_type = coin_type;
+        wallets
+    }
+
+    #[test]
+    fn new_seeds_have_their_own_coin_type() {
+        let mut wallets = Wallets::empty();
+        let address = wallets.new_address().unwrap();
+        let path = format!("m/44'/{}'/0'/0/0", COIN_TYPE);
+        assert_eq!(wallets.get(&address).unwrap().path, Some(path));
+
+        // Seeds saved before they had one keep deriving the same keys
+        let hd: HDSeed = serde_json::from_str(r#"{"seed": "0707", "account": 0}"#).unwrap();
+        assert_eq!(hd.coin_type, LEGACY_COIN_TYPE);
+    }
+
+    #[test]
+    fn discovers_keys_of_the_legacy_coin_type() {
+        let legacy = seeded(LEGACY_COIN_TYPE).derive(0, 0).unwrap();
+        let funding = TX::Standard(StandardTX {
+            inputs: vec![],
+            outputs: vec![TXOutput::new(10, &legacy.address())],
+            replaceable: false,
+            lock_time: 0,
+            memo: String::new(),
+        });
+        let bc = Blockchain::unchecked(vec![funding]);
+
+        let mut wallets = seeded(COIN_TYPE);
+        assert_eq!(wallets.discover(&bc, 5).unwrap(), 1);
+        assert!(wallets.get(&legacy.address()).is_some());
+        assert_eq!(wallets.hd.as_ref().unwrap().coin_type, LEGACY_COIN_TYPE);
+
+        // Seeds that were never used keep their own coin type
+        let mut unused = Wallets::empty();
+        unused.set_seed(&[8; 32]).unwrap();
+        assert_eq!(unused.discover(&bc, 5).unwrap(), 0);
+        assert_eq!(unused.hd.as_ref().unwrap().coin_type, COIN_TYPE);
+    }
+}